@@ -0,0 +1,198 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use anyhow::bail;
+use ipnet::IpNet;
+use log::info;
+
+use crate::conf::{AclAction, AclConfig, PortSpec};
+use crate::metrics::METRICS;
+
+pub type AAcl = Arc<Acl>;
+
+/// Ordered destination rules, the first matching rule decides.
+pub struct Acl {
+    rules: Vec<AclRule>,
+    default_action: AclAction,
+}
+
+struct AclRule {
+    name: String,
+    action: AclAction,
+    hosts: Vec<HostPattern>,
+    ports: Vec<(u16, u16)>,
+    clients: Vec<IpNet>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum HostPattern {
+    Exact(String),
+    /// `.example.com` matches `example.com` and all its subdomains
+    Suffix(String),
+    /// `*` matches any sequence, `?` any single character
+    Glob(String),
+}
+
+impl HostPattern {
+    pub fn parse(pattern: &str) -> Self {
+        let pattern = normalize_host(pattern);
+        if pattern.contains(['*', '?']) {
+            HostPattern::Glob(pattern)
+        } else if let Some(suffix) = pattern.strip_prefix('.') {
+            HostPattern::Suffix(suffix.to_string())
+        } else {
+            HostPattern::Exact(pattern)
+        }
+    }
+
+    /// `host` must be lowercase without trailing dot.
+    pub fn matches(&self, host: &str) -> bool {
+        match self {
+            HostPattern::Exact(exact) => host == exact,
+            HostPattern::Suffix(suffix) => {
+                host == suffix || (host.ends_with(suffix.as_str()) && host[..host.len() - suffix.len()].ends_with('.'))
+            }
+            HostPattern::Glob(glob) => glob_match(glob.as_bytes(), host.as_bytes()),
+        }
+    }
+}
+
+fn normalize_host(host: &str) -> String {
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // position of the last `*` and the text position it is currently matched up to
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    backtrack = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|it| *it == b'*')
+}
+
+impl AclRule {
+    fn matches(&self, client: IpAddr, host: &str, port: u16) -> bool {
+        (self.hosts.is_empty() || self.hosts.iter().any(|it| it.matches(host)))
+            && (self.ports.is_empty() || self.ports.iter().any(|(start, end)| (*start..=*end).contains(&port)))
+            && (self.clients.is_empty() || self.clients.iter().any(|it| it.contains(&client)))
+    }
+}
+
+impl Acl {
+    pub fn new(acl_config: &AclConfig) -> anyhow::Result<Self> {
+        let mut rules = vec![];
+        for (idx, rule) in acl_config.rules.iter().enumerate() {
+            let mut ports = vec![];
+            for port in &rule.ports {
+                ports.push(parse_port_spec(port)?);
+            }
+            rules.push(AclRule {
+                name: rule.name.clone().unwrap_or_else(|| format!("#{}", idx + 1)),
+                action: rule.action,
+                hosts: rule.hosts.iter().map(|it| HostPattern::parse(it)).collect(),
+                ports,
+                clients: rule.clients.clone(),
+            });
+        }
+        Ok(Self { rules, default_action: acl_config.default })
+    }
+
+    /// Returns the name of the deciding rule, `None` when no rule matched.
+    fn decide(&self, client: IpAddr, host: &str, port: u16) -> (AclAction, Option<&str>) {
+        let host = normalize_host(host);
+        let client = client.to_canonical();
+        self.rules.iter()
+            .find(|rule| rule.matches(client, &host, port))
+            .map(|rule| (rule.action, Some(rule.name.as_str())))
+            .unwrap_or((self.default_action, None))
+    }
+
+    /// Checks whether `client_addr` may connect to `host:port`, denials are logged with the matching rule.
+    pub fn is_allowed(&self, tunnel_name: &str, client_addr: SocketAddr, host: &str, port: u16) -> bool {
+        match self.decide(client_addr.ip(), host, port) {
+            (AclAction::Allow, _) => true,
+            (AclAction::Deny, rule) => {
+                info!("[{}] {} -> {}:{} denied by acl rule: {}", tunnel_name, client_addr, host, port, rule.unwrap_or("default"));
+                METRICS.connection_failed(tunnel_name, "acl_denied");
+                false
+            }
+        }
+    }
+}
+
+fn parse_port_spec(port: &PortSpec) -> anyhow::Result<(u16, u16)> {
+    match port {
+        PortSpec::Port(port) => Ok((*port, *port)),
+        PortSpec::Range(range) => {
+            let Some((start, end)) = range.split_once('-') else {
+                bail!("invalid port range: {}", range);
+            };
+            let (start, end) = (start.trim().parse::<u16>()?, end.trim().parse::<u16>()?);
+            if start > end {
+                bail!("invalid port range: {}", range);
+            }
+            Ok((start, end))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_pattern() {
+        assert!(HostPattern::parse("Example.com.").matches("example.com"));
+        assert!(!HostPattern::parse("example.com").matches("www.example.com"));
+        assert!(HostPattern::parse(".example.com").matches("example.com"));
+        assert!(HostPattern::parse(".example.com").matches("a.b.example.com"));
+        assert!(!HostPattern::parse(".example.com").matches("badexample.com"));
+        assert!(HostPattern::parse("*.example.*").matches("www.example.org"));
+        assert!(HostPattern::parse("10.0.?.1").matches("10.0.3.1"));
+        assert!(!HostPattern::parse("*.example.com").matches("example.com"));
+    }
+
+    #[test]
+    fn test_decide() -> anyhow::Result<()> {
+        let acl_config: AclConfig = toml::from_str(r#"
+default = "deny"
+
+[[rules]]
+name = "no-metadata"
+action = "deny"
+hosts = ["169.254.169.254"]
+
+[[rules]]
+name = "lan-web"
+action = "allow"
+ports = [80, "443-444"]
+clients = ["192.168.0.0/16"]
+"#)?;
+        let acl = Acl::new(&acl_config)?;
+        let lan_client = "192.168.1.10".parse()?;
+        assert_eq!(acl.decide(lan_client, "169.254.169.254", 80), (AclAction::Deny, Some("no-metadata")));
+        assert_eq!(acl.decide(lan_client, "example.com", 444), (AclAction::Allow, Some("lan-web")));
+        assert_eq!(acl.decide(lan_client, "example.com", 22), (AclAction::Deny, None));
+        assert_eq!(acl.decide("::ffff:192.168.1.10".parse()?, "example.com", 80), (AclAction::Allow, Some("lan-web")));
+        assert_eq!(acl.decide("10.0.0.1".parse()?, "example.com", 80), (AclAction::Deny, None));
+        Ok(())
+    }
+}
@@ -0,0 +1,155 @@
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::bail;
+use log::{error, info};
+use serde_json::json;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, UnixListener};
+use tokio_util::sync::CancellationToken;
+
+use crate::conf::AdminConfig;
+use crate::connection_handle::ATunnelContext;
+use crate::http_server;
+
+pub type AUnixSocket = Arc<UnixSocket>;
+
+/// Bound unix socket of the admin api, the file is removed once the last listener using it is gone.
+pub struct UnixSocket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl UnixSocket {
+    pub fn bind(path: &Path) -> anyhow::Result<Self> {
+        // a socket left behind by a previous run makes bind fail
+        if std::fs::symlink_metadata(path).is_ok_and(|it| it.file_type().is_socket()) {
+            std::fs::remove_file(path)?;
+        }
+        Ok(Self { listener: UnixListener::bind(path)?, path: path.to_path_buf() })
+    }
+}
+
+impl Drop for UnixSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// JSON api over plain http:
+///
+/// - `GET /connections` lists the connections of every tunnel
+/// - `DELETE /connections/{id}` closes one of them
+/// - `GET /dns/cache` lists recent DNS lookups whose answers have not expired, `DELETE /dns/cache` flushes the resolver cache
+pub struct AdminServer {
+    admin_config: AdminConfig,
+    ctx: ATunnelContext,
+}
+
+impl AdminServer {
+    pub fn new(admin_config: AdminConfig, ctx: ATunnelContext) -> anyhow::Result<Self> {
+        if admin_config.unix_socket.is_none() {
+            let bind_addr = admin_config.listener.listen_addr()?;
+            if !bind_addr.ip().is_loopback() {
+                bail!("admin listener must be bound to a loopback address, got {}", bind_addr);
+            }
+        }
+        Ok(Self { admin_config, ctx })
+    }
+
+    fn name(&self) -> &str {
+        self.admin_config.listener.name.as_deref().unwrap_or("admin")
+    }
+
+    /// Accepts connections on `listener` until `shutdown` is cancelled, they are not listed as connections themselves.
+    pub async fn serve(self: Arc<Self>, listener: Arc<TcpListener>, shutdown: CancellationToken) -> anyhow::Result<()> {
+        http_server::serve(self.name(), &self.admin_config.listener, listener, shutdown, |mut stream| {
+            let server = self.clone();
+            async move { server.handle_request(&mut stream).await }
+        }).await
+    }
+
+    /// Accepts connections on the unix socket until `shutdown` is cancelled.
+    pub async fn serve_unix(self: Arc<Self>, socket: AUnixSocket, shutdown: CancellationToken) -> anyhow::Result<()> {
+        let path = socket.path.display();
+        info!("[{}] listening on: {}", self.name(), path);
+        loop {
+            let (mut stream, _) = tokio::select! {
+                accepted = socket.listener.accept() => accepted?,
+                _ = shutdown.cancelled() => {
+                    info!("[{}] stopped listening on: {}", self.name(), path);
+                    return Ok(());
+                }
+            };
+            tokio::spawn({
+                let server = self.clone();
+                async move {
+                    if let Err(e) = server.handle_request(&mut stream).await {
+                        error!("[{}] process connection error: {:?}", server.name(), e);
+                    }
+                }
+            });
+        }
+    }
+
+    async fn handle_request<S: AsyncRead + AsyncWrite + Unpin>(&self, stream: &mut S) -> anyhow::Result<()> {
+        let request = http_server::read_request(stream).await?;
+        let path = request.path.split('?').next().unwrap_or_default();
+        let segments = path.trim_matches('/').split('/').collect::<Vec<_>>();
+        let not_found = |what: &str| ("404 Not Found", json!({ "error": format!("{} not found", what) }));
+        let (status, body) = match (request.method.as_str(), segments.as_slice()) {
+            ("GET", ["connections"]) => {
+                let connections = self.ctx.connections.list().iter().map(|it| it.snapshot()).collect::<Vec<_>>();
+                ("200 OK", serde_json::to_value(connections)?)
+            }
+            ("DELETE", ["connections", id]) => match id.parse::<u64>() {
+                Ok(id) if self.ctx.connections.close(id) => {
+                    info!("[{}] closed connection {}", self.name(), id);
+                    ("200 OK", json!({ "closed": id }))
+                }
+                _ => not_found("connection"),
+            },
+            ("GET", ["dns", "cache"]) => ("200 OK", serde_json::to_value(self.ctx.tcp_connector().dns_resolver().recent_lookups())?),
+            ("DELETE", ["dns", "cache"]) => {
+                let flushed = self.ctx.tcp_connector().dns_resolver().flush_cache();
+                info!("[{}] flushed dns cache, {} hosts", self.name(), flushed);
+                ("200 OK", json!({ "flushed": flushed }))
+            }
+            _ => not_found(path),
+        };
+        http_server::write_response(stream, status, "application/json", &serde_json::to_vec(&body)?).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    use super::*;
+    use crate::acl::Acl;
+    use crate::bandwidth::BandwidthLimits;
+    use crate::connection_handle::TunnelContext;
+    use crate::tcp_connector::TcpConnector;
+
+    #[tokio::test]
+    async fn test_admin_request_not_listed() -> anyhow::Result<()> {
+        let tcp_connector = Arc::new(TcpConnector::new(Default::default())?);
+        let acl = Arc::new(Acl::new(&Default::default())?);
+        let ctx = Arc::new(TunnelContext::new(tcp_connector, acl, Arc::new(BandwidthLimits::new(&Default::default()))));
+        let server = Arc::new(AdminServer::new(toml::from_str("listen = \"127.0.0.1:0\"\n")?, ctx.clone())?);
+        let listener = Arc::new(TcpListener::bind("127.0.0.1:0").await?);
+        let addr = listener.local_addr()?;
+        tokio::spawn(server.serve(listener, CancellationToken::new()));
+
+        let mut stream = TcpStream::connect(addr).await?;
+        stream.write_all(b"GET /connections HTTP/1.1\r\n\r\n").await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.ends_with("\r\n\r\n[]"), "{}", response);
+        assert!(ctx.connections.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,167 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::bail;
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use log::{error, info, warn};
+use sha1::{Digest, Sha1};
+
+pub type AHtpasswd = Arc<Htpasswd>;
+
+/// The file is looked at no more often than this, its stat blocks the calling thread.
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// User store backed by an htpasswd file, reloaded when its mtime changes, checked at most once a second.
+pub struct Htpasswd {
+    path: PathBuf,
+    checked_at: Mutex<Instant>,
+    state: RwLock<HtpasswdState>,
+}
+
+struct HtpasswdState {
+    modified: Option<SystemTime>,
+    users: HashMap<String, String>,
+}
+
+impl Htpasswd {
+    pub fn load(path: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let path = path.into();
+        let modified = std::fs::metadata(&path)?.modified().ok();
+        let users = parse_htpasswd(&std::fs::read_to_string(&path)?)?;
+        info!("loaded {} users from {:?}", users.len(), path);
+        Ok(Self { path, checked_at: Mutex::new(Instant::now()), state: RwLock::new(HtpasswdState { modified, users }) })
+    }
+
+    fn reload_if_changed(&self) {
+        {
+            let mut checked_at = self.checked_at.lock().unwrap();
+            if checked_at.elapsed() < RELOAD_CHECK_INTERVAL {
+                return;
+            }
+            *checked_at = Instant::now();
+        }
+        let modified = match std::fs::metadata(&self.path).and_then(|it| it.modified()) {
+            Ok(modified) => Some(modified),
+            Err(err) => {
+                error!("failed to stat htpasswd file {:?}, keep old users, err: {:?}", self.path, err);
+                return;
+            }
+        };
+        if self.state.read().unwrap().modified == modified {
+            return;
+        }
+        let users = std::fs::read_to_string(&self.path)
+            .map_err(anyhow::Error::from)
+            .and_then(|it| parse_htpasswd(&it));
+        let mut state = self.state.write().unwrap();
+        state.modified = modified;
+        match users {
+            Ok(users) => {
+                info!("reloaded {} users from {:?}", users.len(), self.path);
+                state.users = users;
+            }
+            Err(err) => {
+                error!("failed to reload htpasswd file {:?}, keep old users, err: {:?}", self.path, err);
+            }
+        }
+    }
+
+    pub async fn verify(&self, username: &str, password: &str) -> bool {
+        self.reload_if_changed();
+        let hash = match self.state.read().unwrap().users.get(username) {
+            Some(hash) => hash.clone(),
+            None => return false,
+        };
+        let password = password.to_string();
+        // bcrypt is deliberately slow, keep it off the io threads
+        tokio::task::spawn_blocking(move || verify_hash(&hash, &password))
+            .await
+            .unwrap_or(false)
+    }
+}
+
+fn parse_htpasswd(content: &str) -> anyhow::Result<HashMap<String, String>> {
+    let mut users = HashMap::new();
+    for (line_no, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((username, hash)) = line.split_once(':') else {
+            bail!("invalid htpasswd line {}", line_no + 1);
+        };
+        if !is_supported_hash(hash) {
+            warn!("unsupported hash for user {} at line {}, only bcrypt and {{SHA}} are supported", username, line_no + 1);
+            continue;
+        }
+        users.insert(username.to_string(), hash.to_string());
+    }
+    Ok(users)
+}
+
+fn is_supported_hash(hash: &str) -> bool {
+    hash.starts_with("$2y$") || hash.starts_with("$2a$") || hash.starts_with("$2b$") || hash.starts_with("{SHA}")
+}
+
+fn verify_hash(hash: &str, password: &str) -> bool {
+    if let Some(sha) = hash.strip_prefix("{SHA}") {
+        let digest = Sha1::digest(password.as_bytes());
+        return BASE64_STANDARD.encode(digest) == sha;
+    }
+    bcrypt::verify(password, hash).unwrap_or(false)
+}
+
+/// Decodes a `Basic` credential, e.g. the value of a `Proxy-Authorization` header.
+pub fn parse_basic_credentials(value: &str) -> Option<(String, String)> {
+    let (scheme, token) = value.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = BASE64_STANDARD.decode(token.trim()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some((username.to_string(), password.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_basic_credentials() {
+        let header = format!("Basic {}", BASE64_STANDARD.encode("arthur:pass:word"));
+        assert_eq!(parse_basic_credentials(&header), Some(("arthur".to_string(), "pass:word".to_string())));
+        assert_eq!(parse_basic_credentials("Bearer abc"), None);
+        assert_eq!(parse_basic_credentials("Basic !!!"), None);
+    }
+
+    #[test]
+    fn test_verify_hash() {
+        // generated by `htpasswd -nbs arthur secret`
+        let users = parse_htpasswd("# comment\narthur:{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ=\nbob:$apr1$x$y\n").unwrap();
+        assert_eq!(users.len(), 1);
+        assert!(verify_hash(&users["arthur"], "secret"));
+        assert!(!verify_hash(&users["arthur"], "wrong"));
+
+        let bcrypt_hash = bcrypt::hash("secret", 4).unwrap();
+        assert!(verify_hash(&bcrypt_hash, "secret"));
+        assert!(!verify_hash(&bcrypt_hash, "wrong"));
+    }
+
+    #[tokio::test]
+    async fn test_reload_throttled() {
+        let path = std::env::temp_dir().join(format!("http-tunnel-rs-htpasswd-{}", std::process::id()));
+        std::fs::write(&path, "arthur:{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ=\n").unwrap();
+        let htpasswd = Htpasswd::load(&path).unwrap();
+        assert!(htpasswd.verify("arthur", "secret").await);
+
+        std::fs::write(&path, "ford:{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ=\n").unwrap();
+        assert!(!htpasswd.verify("ford", "secret").await);
+        *htpasswd.checked_at.lock().unwrap() -= RELOAD_CHECK_INTERVAL;
+        assert!(htpasswd.verify("ford", "secret").await);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, Weak};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::conf::{BandwidthConfig, RateLimitConfig};
+use crate::limits::TokenBucket;
+
+pub type ARateLimiter = Arc<RateLimiter>;
+pub type ABandwidthLimits = Arc<BandwidthLimits>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// client to target
+    Upload,
+    /// target to client
+    Download,
+}
+
+/// Upload and download buckets of one scope, in bytes.
+pub struct RateLimiter {
+    upload: Option<Mutex<TokenBucket>>,
+    download: Option<Mutex<TokenBucket>>,
+}
+
+impl RateLimiter {
+    /// None when neither direction is limited.
+    fn new(config: &RateLimitConfig) -> Option<Self> {
+        // a second of traffic may be sent at once
+        let bucket = |rate: Option<u64>| rate.filter(|it| *it > 0).map(|it| Mutex::new(TokenBucket::new(it as f64, it as f64)));
+        let limiter = Self { upload: bucket(config.upload), download: bucket(config.download) };
+        (limiter.upload.is_some() || limiter.download.is_some()).then_some(limiter)
+    }
+
+    fn bucket(&self, direction: Direction) -> Option<&Mutex<TokenBucket>> {
+        match direction {
+            Direction::Upload => self.upload.as_ref(),
+            Direction::Download => self.download.as_ref(),
+        }
+    }
+}
+
+/// Rate limits of a listener, or of the whole proxy for the top level `[bandwidth]`.
+pub struct BandwidthLimits {
+    total: Option<ARateLimiter>,
+    per_client: RateLimitConfig,
+    /// kept while a connection of the client holds it
+    clients: Mutex<HashMap<IpAddr, Weak<RateLimiter>>>,
+    per_connection: RateLimitConfig,
+}
+
+impl BandwidthLimits {
+    pub fn new(config: &BandwidthConfig) -> Self {
+        Self {
+            total: RateLimiter::new(&config.total).map(Arc::new),
+            per_client: config.per_client.clone(),
+            clients: Mutex::new(HashMap::new()),
+            per_connection: config.per_connection.clone(),
+        }
+    }
+
+    /// Limiters a new connection from `client` is shaped by, empty when nothing is limited.
+    pub fn rate_limiters(&self, client: IpAddr) -> Vec<ARateLimiter> {
+        let mut limiters = vec![];
+        limiters.extend(self.total.clone());
+        if let Some(limiter) = RateLimiter::new(&self.per_connection) {
+            limiters.push(Arc::new(limiter));
+        }
+        if let Some(limiter) = self.client_limiter(client.to_canonical()) {
+            limiters.push(limiter);
+        }
+        limiters
+    }
+
+    fn client_limiter(&self, client: IpAddr) -> Option<ARateLimiter> {
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(limiter) = clients.get(&client).and_then(Weak::upgrade) {
+            return Some(limiter);
+        }
+        let limiter = Arc::new(RateLimiter::new(&self.per_client)?);
+        clients.retain(|_, it| it.strong_count() > 0);
+        clients.insert(client, Arc::downgrade(&limiter));
+        Some(limiter)
+    }
+}
+
+/// Largest burst the limiters in `direction` allow, `None` when it is not limited.
+pub fn max_burst(limiters: &[ARateLimiter], direction: Direction) -> Option<usize> {
+    limiters.iter()
+        .filter_map(|it| it.bucket(direction))
+        .map(|bucket| bucket.lock().unwrap().capacity() as usize)
+        .min()
+}
+
+/// Charges `bytes` to every limiter and waits until the most indebted one is paid back.
+pub async fn throttle(limiters: &[ARateLimiter], direction: Direction, bytes: usize) {
+    let wait = limiters.iter()
+        .filter_map(|it| it.bucket(direction))
+        .map(|bucket| {
+            let mut bucket = bucket.lock().unwrap();
+            bucket.take(bytes as f64);
+            bucket.wait_time(0.0)
+        })
+        .max()
+        .unwrap_or_default();
+    if !wait.is_zero() {
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// Copies `r` to `w` until EOF, then shuts down `w` so the peer sees the half-close.
+pub async fn copy_shaped<R, W>(r: &mut R, w: &mut W, buf_size: usize, limiters: &[ARateLimiter], direction: Direction, mut on_bytes: impl FnMut(u64)) -> std::io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0; buf_size];
+    loop {
+        let n = r.read(&mut buf).await?;
+        if n == 0 {
+            w.shutdown().await?;
+            return Ok(());
+        }
+        w.write_all(&buf[..n]).await?;
+        on_bytes(n as u64);
+        throttle(limiters, direction, n).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_copy_shaped() {
+        let config: BandwidthConfig = toml::from_str(r#"
+[total]
+download = 100000
+[per_client]
+upload = 20000
+"#).unwrap();
+        let limits = BandwidthLimits::new(&config);
+        let client: IpAddr = "10.0.0.1".parse().unwrap();
+        let limiters = limits.rate_limiters(client);
+        assert_eq!(limiters.len(), 2);
+        assert_eq!((max_burst(&limiters, Direction::Upload), max_burst(&limiters[1..], Direction::Download)), (Some(20000), None));
+        // connections of one client share its limiter
+        assert!(Arc::ptr_eq(&limiters[1], &limits.rate_limiters(client)[1]));
+        assert!(!Arc::ptr_eq(&limiters[1], &limits.rate_limiters("10.0.0.2".parse().unwrap())[1]));
+
+        // the first second of traffic is the burst, the rest is paid at the rate
+        let data = vec![7u8; 30000];
+        let mut src = data.as_slice();
+        let (mut w, mut r) = tokio::io::duplex(64 * 1024);
+        let start = Instant::now();
+        let mut copied = 0;
+        let mut out = vec![];
+        tokio::try_join!(
+            copy_shaped(&mut src, &mut w, 4096, &limiters, Direction::Upload, |n| copied += n),
+            r.read_to_end(&mut out),
+        ).unwrap();
+        assert_eq!((copied, out.len()), (30000, 30000));
+        assert!(start.elapsed() >= Duration::from_millis(400), "{:?}", start.elapsed());
+    }
+}
@@ -0,0 +1,76 @@
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_util::bytes::BytesMut;
+
+/// Accepted connection together with the bytes already read from it, e.g. behind a PROXY protocol header.
+/// Reads return those bytes first.
+pub struct ClientStream {
+    stream: TcpStream,
+    received: BytesMut,
+}
+
+impl ClientStream {
+    pub fn new(stream: TcpStream, received: BytesMut) -> Self {
+        Self { stream, received }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.local_addr()
+    }
+}
+
+impl AsyncRead for ClientStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.received.is_empty() {
+            let n = this.received.len().min(buf.remaining());
+            buf.put_slice(&this.received.split_to(n));
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ClientStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_received_first() -> anyhow::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let mut client = TcpStream::connect(listener.local_addr()?).await?;
+        client.write_all(b" world").await?;
+        client.shutdown().await?;
+        let (stream, _) = listener.accept().await?;
+        let mut stream = ClientStream::new(stream, BytesMut::from("hello"));
+        let mut buf = [0u8; 3];
+        stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"hel");
+        let mut rest = String::new();
+        stream.read_to_string(&mut rest).await?;
+        assert_eq!(rest, "lo world");
+        Ok(())
+    }
+}
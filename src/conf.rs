@@ -1,8 +1,12 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
 use anyhow::bail;
 use clap::Parser;
-use serde::Deserialize;
+use ipnet::IpNet;
+use serde::de::{DeserializeOwned, Error};
+use serde::{Deserialize, Deserializer};
 
 
 #[derive(Parser, Debug)]
@@ -15,55 +19,397 @@ struct Cli {
 
 #[derive(Deserialize, Debug)]
 pub struct Config {
-    pub http: Option<HttpConfig>,
-    pub https: Option<HttpsConfig>,
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub http: Vec<HttpConfig>,
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub https: Vec<HttpsConfig>,
+    pub socks5: Option<Socks5Config>,
+    pub socks4: Option<Socks4Config>,
     #[serde(default)]
     pub tcp: Vec<TcpConfig>,
+    pub metrics: Option<MetricsConfig>,
+    pub admin: Option<AdminConfig>,
+    #[serde(default)]
+    pub shutdown: ShutdownConfig,
+    #[serde(default)]
+    pub reload: ReloadConfig,
+    #[serde(default)]
+    pub acl: AclConfig,
+    #[serde(default)]
+    pub bandwidth: BandwidthConfig,
     #[serde(flatten, default)]
     pub tunnel_config: TunnelConfig,
 
 }
 
+/// Accepts a single `[table]` as well as an `[[array]]` of tables.
+fn one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: DeserializeOwned,
+{
+    match toml::Value::deserialize(deserializer)? {
+        toml::Value::Array(values) => values.into_iter().map(|it| T::deserialize(it).map_err(D::Error::custom)).collect(),
+        value => Ok(vec![T::deserialize(value).map_err(D::Error::custom)?]),
+    }
+}
+
 #[derive(Deserialize, Clone, Debug, Default)]
 pub struct TunnelConfig {
+    #[serde(default)]
     pub target_connection: TargetConnectionConfig,
 }
 
 
 impl Config {
-    pub fn from_cmd_line() -> anyhow::Result<Config> {
+    /// Path given with `-c`, read again on reload.
+    pub fn path_from_cmd_line() -> anyhow::Result<PathBuf> {
         let cli = Cli::parse();
-        if let Some(config_path) = cli.config {
-            let config_str = std::fs::read_to_string(config_path)?;
-            let config: Config = toml::from_str(&config_str)?;
-            return Ok(config);
+        match cli.config {
+            Some(config_path) => Ok(PathBuf::from(config_path)),
+            None => bail!("Config file not found"),
         }
-        bail!("Config file not found")
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Config> {
+        let config_str = std::fs::read_to_string(path)?;
+        let config: Config = toml::from_str(&config_str)?;
+        Ok(config)
     }
 }
 
 
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct AclConfig {
+    /// action when no rule matches
+    #[serde(default)]
+    pub default: AclAction,
+    #[serde(default)]
+    pub rules: Vec<AclRuleConfig>,
+}
+
+/// A rule matches when every criterion that is set matches.
 #[derive(Deserialize, Debug, Clone)]
-pub struct HttpConfig {
-    pub listen_port: u16,
+pub struct AclRuleConfig {
+    pub name: Option<String>,
+    pub action: AclAction,
+    /// `example.com`, `.example.com` for the domain and its subdomains, or globs like `*.example.*`
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    /// ports like `443` or ranges like `"8000-8999"`
+    #[serde(default)]
+    pub ports: Vec<PortSpec>,
+    /// client source CIDRs
+    #[serde(default)]
+    pub clients: Vec<IpNet>,
 }
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AclAction {
+    #[default]
+    Allow,
+    Deny,
+}
+
 #[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum PortSpec {
+    Port(u16),
+    Range(String),
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct ListenerConfig {
+    /// name used in logs, defaults to the kind of tunnel
+    pub name: Option<String>,
+    /// full bind address, e.g. `127.0.0.1:8081` or `[::1]:8443`
+    pub listen: Option<SocketAddr>,
+    /// shorthand for `listen`, bound on `listen_ip`
+    pub listen_port: Option<u16>,
+    /// `::` listens on ipv6 and ipv4 (dual-stack)
+    #[serde(default = "default_listen_ip")]
+    pub listen_ip: IpAddr,
+    /// client source CIDRs allowed to connect, empty allows everyone
+    #[serde(default)]
+    pub allowed_clients: Vec<IpNet>,
+    /// PROXY protocol header expected in front of the client's bytes, e.g. behind a load balancer
+    #[serde(default)]
+    pub accept_proxy_protocol: ProxyProtocolMode,
+    /// peers allowed to send a PROXY protocol header, empty trusts no one
+    #[serde(default)]
+    pub trusted_sources: Vec<IpNet>,
+    /// connections served at once, http listeners answer further ones with 503
+    pub max_connections: Option<usize>,
+    /// connections served at once per client ip, http listeners answer further ones with 429
+    pub max_connections_per_client: Option<usize>,
+    /// new connections per second per client ip, bursts up to the same number
+    pub max_connection_rate_per_client: Option<u32>,
+    /// how long a connection over a limit waits for a slot, unset rejects it right away
+    #[serde(default, with = "humantime_serde")]
+    pub limit_queue_timeout: Option<Duration>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyProtocolMode {
+    #[default]
+    None,
+    /// trusted peers may send a header, connections without one keep the peer address
+    Optional,
+    /// every connection must come from a trusted peer and start with a header
+    Required,
+}
+
+fn default_listen_ip() -> IpAddr {
+    IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+}
+
+impl ListenerConfig {
+    pub fn listen_addr(&self) -> anyhow::Result<SocketAddr> {
+        match (self.listen, self.listen_port) {
+            (Some(listen), None) => Ok(listen),
+            (None, Some(listen_port)) => Ok(SocketAddr::new(self.listen_ip, listen_port)),
+            (Some(_), Some(_)) => bail!("only one of listen and listen_port can be set"),
+            (None, None) => bail!("listen or listen_port is required"),
+        }
+    }
+
+    pub fn is_client_allowed(&self, client: IpAddr) -> bool {
+        let client = client.to_canonical();
+        self.allowed_clients.is_empty() || self.allowed_clients.iter().any(|it| it.contains(&client))
+    }
+
+    pub fn is_trusted_source(&self, peer: IpAddr) -> bool {
+        let peer = peer.to_canonical();
+        self.trusted_sources.iter().any(|it| it.contains(&peer))
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct HttpConfig {
+    #[serde(flatten)]
+    pub listener: ListenerConfig,
+    pub auth: Option<AuthConfig>,
+    #[serde(default)]
+    pub forwarding: ForwardingConfig,
+    pub error_page: Option<ErrorPageConfig>,
+    #[serde(default)]
+    pub bandwidth: BandwidthConfig,
+}
+
+/// Body of the responses the proxy answers itself, see `ErrorPage` for the placeholders.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct ErrorPageConfig {
+    pub template: String,
+    #[serde(default = "default_error_page_content_type")]
+    pub content_type: String,
+}
+
+fn default_error_page_content_type() -> String {
+    "text/html; charset=utf-8".to_string()
+}
+
+/// Headers added to plain HTTP requests, all off by default.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ForwardingConfig {
+    #[serde(default)]
+    pub via: bool,
+    /// RFC 7239 `Forwarded: for=...;host=...;proto=http`
+    #[serde(default)]
+    pub forwarded: bool,
+    #[serde(default)]
+    pub x_forwarded_for: bool,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct AuthConfig {
+    /// htpasswd file with bcrypt or {SHA} hashes, reloaded when it changes
+    pub htpasswd: String,
+    #[serde(default = "default_auth_realm")]
+    pub realm: String,
+}
+
+fn default_auth_realm() -> String {
+    "http-tunnel-rs".to_string()
+}
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 pub struct HttpsConfig {
-    pub listen_port: u16,
+    #[serde(flatten)]
+    pub listener: ListenerConfig,
+    /// PROXY protocol header sent to backends, unless a route decides otherwise
+    pub proxy_protocol: Option<ProxyProtocolVersion>,
+    #[serde(default)]
+    pub routes: Vec<HttpsRouteConfig>,
+    #[serde(default)]
+    pub bandwidth: BandwidthConfig,
 }
-#[derive(Deserialize, Debug, Clone)]
+
+/// Settings for the SNIs matching one of the `sni` host patterns, the first matching route applies.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct HttpsRouteConfig {
+    pub sni: Vec<String>,
+    pub proxy_protocol: Option<ProxyProtocolVersion>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyProtocolVersion {
+    V1,
+    V2,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Socks5Config {
+    #[serde(flatten)]
+    pub listener: ListenerConfig,
+    /// enables username/password authentication (RFC 1929), only `htpasswd` is used
+    pub auth: Option<AuthConfig>,
+}
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Socks4Config {
+    #[serde(flatten)]
+    pub listener: ListenerConfig,
+}
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 pub struct TcpConfig {
-    pub listen_port: u16,
+    #[serde(flatten)]
+    pub listener: ListenerConfig,
     pub remote_addr: String,
+    /// PROXY protocol header announcing the client address to the backend
+    pub proxy_protocol: Option<ProxyProtocolVersion>,
+    #[serde(default)]
+    pub bandwidth: BandwidthConfig,
+}
+/// Traffic shaping of a listener, or of all tunnels in the top level `[bandwidth]`, every limit that is set applies.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct BandwidthConfig {
+    /// shared by all connections
+    pub total: RateLimitConfig,
+    /// shared by the connections of one client ip
+    pub per_client: RateLimitConfig,
+    pub per_connection: RateLimitConfig,
 }
 
+/// Bytes per second, `upload` is from the client to the target.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct RateLimitConfig {
+    pub upload: Option<u64>,
+    pub download: Option<u64>,
+}
+/// Prometheus scrape endpoint, `/metrics`.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct MetricsConfig {
+    #[serde(flatten)]
+    pub listener: ListenerConfig,
+}
+/// JSON api to inspect and close connections, bound on loopback or a unix socket.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct AdminConfig {
+    #[serde(flatten)]
+    pub listener: ListenerConfig,
+    /// listens on this unix socket path instead of `listen`
+    pub unix_socket: Option<PathBuf>,
+}
 
-#[derive(Deserialize, Debug, Clone)]
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
 pub struct TargetConnectionConfig {
-    #[serde(with = "humantime_serde")]
+    #[serde(default, with = "humantime_serde")]
     pub dns_cache_ttl: Option<Duration>,
     #[serde(with = "humantime_serde")]
     pub connect_timeout: Duration,
+    /// total budget for resolving and connecting, retries and upstream handshakes included
+    #[serde(default, with = "humantime_serde")]
+    pub connect_deadline: Option<Duration>,
+    pub retry: RetryConfig,
+    pub address_family: AddressFamily,
+    /// parent proxies, the target is reached by issuing CONNECT through each of them in order
+    #[serde(default)]
+    pub upstream: Vec<UpstreamProxyConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct RetryConfig {
+    /// connect attempts per target, each attempt races the resolved addresses
+    pub max_attempts: u32,
+    /// delay before the second attempt, doubled for each further attempt
+    #[serde(with = "humantime_serde")]
+    pub backoff: Duration,
+    /// try the other resolved addresses, otherwise only the preferred one is retried
+    pub failover: bool,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 1,
+            backoff: Duration::from_millis(200),
+            failover: true,
+        }
+    }
+}
+
+/// What happens on SIGTERM or SIGINT.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ShutdownConfig {
+    /// how long running connections may finish after listeners stopped, then they are closed
+    #[serde(with = "humantime_serde")]
+    pub drain_timeout: Duration,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self { drain_timeout: Duration::from_secs(30) }
+    }
+}
+
+/// Reloading on SIGHUP is always on, watching the config file is optional.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct ReloadConfig {
+    /// how often the config file is checked for changes
+    #[serde(default, with = "humantime_serde")]
+    pub watch_interval: Option<Duration>,
+}
+
+/// Which address families are resolved for targets, and which one is tried first.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AddressFamily {
+    Ipv4Only,
+    Ipv6Only,
+    #[default]
+    PreferIpv4,
+    PreferIpv6,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct UpstreamProxyConfig {
+    /// `host:port` of the parent proxy
+    pub addr: String,
+    #[serde(default)]
+    pub protocol: UpstreamProtocol,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// socks5 only, pass hostnames to the parent (socks5h) instead of resolving them locally
+    #[serde(default = "default_remote_dns")]
+    pub remote_dns: bool,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum UpstreamProtocol {
+    #[default]
+    Http,
+    Socks5,
+}
+
+fn default_remote_dns() -> bool {
+    true
 }
 
 
@@ -72,15 +418,21 @@ impl Default for TargetConnectionConfig {
         Self {
             dns_cache_ttl: None,
             connect_timeout: Duration::from_secs(10),
+            connect_deadline: None,
+            retry: RetryConfig::default(),
+            address_family: AddressFamily::default(),
+            upstream: vec![],
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::time::Duration;
+
     use log::info;
 
-    use crate::conf::Config;
+    use crate::conf::{AddressFamily, Config, ProxyProtocolMode, ProxyProtocolVersion, UpstreamProtocol};
 
     #[test]
     fn test_conf_parse() {
@@ -88,6 +440,21 @@ mod tests {
 [http]
 listen_port = 8081
 
+[http.auth]
+htpasswd = "/etc/http-tunnel/htpasswd"
+
+[http.forwarding]
+via = true
+x_forwarded_for = true
+
+[http.error_page]
+template = "/etc/http-tunnel/error.html"
+
+[socks5]
+listen_port = 1080
+listen_ip = "::"
+allowed_clients = ["192.168.0.0/16", "fd00::/8"]
+
 [[tcp]]
 listen_port = 8082
 remote_addr = "192.168.31.197:22"
@@ -95,6 +462,52 @@ remote_addr = "192.168.31.197:22"
 [[tcp]]
 listen_port = 8083
 remote_addr = "192.168.31.197:80"
+proxy_protocol = "v2"
+
+[metrics]
+listen_ip = "127.0.0.1"
+listen_port = 9090
+
+[admin]
+unix_socket = "/run/http-tunnel/admin.sock"
+
+[shutdown]
+drain_timeout = "1m"
+
+[reload]
+watch_interval = "5s"
+
+[bandwidth.total]
+download = 1000000
+
+[target_connection]
+connect_timeout = "2s"
+address_family = "prefer_ipv6"
+connect_deadline = "15s"
+
+[target_connection.retry]
+max_attempts = 3
+failover = false
+
+[[target_connection.upstream]]
+addr = "proxy.corp.example:3128"
+username = "arthur"
+password = "secret"
+
+[[target_connection.upstream]]
+addr = "10.0.0.1:1080"
+protocol = "socks5"
+remote_dns = false
+
+[acl]
+default = "allow"
+
+[[acl.rules]]
+name = "no-ssh"
+action = "deny"
+hosts = [".example.com"]
+ports = [22, "2200-2299"]
+clients = ["10.0.0.0/8"]
 
 [client_connection]
 initiation_timeout_seconds = 10
@@ -107,7 +520,117 @@ idle_timeout_seconds = 30
 "#;
 
         let config: Config = toml::from_str(conf).unwrap();
+        let auth = config.http[0].auth.as_ref().unwrap();
+        assert_eq!(auth.realm, "http-tunnel-rs");
+        let forwarding = &config.http[0].forwarding;
+        assert!(forwarding.via && !forwarding.forwarded && forwarding.x_forwarded_for);
+        assert_eq!(config.http[0].error_page.as_ref().unwrap().content_type, "text/html; charset=utf-8");
+        let socks5 = config.socks5.as_ref().unwrap();
+        assert!(socks5.auth.is_none());
+        assert_eq!(socks5.listener.listen_addr().unwrap(), "[::]:1080".parse().unwrap());
+        assert!(socks5.listener.is_client_allowed("::ffff:192.168.1.1".parse().unwrap()));
+        assert!(!socks5.listener.is_client_allowed("10.0.0.1".parse().unwrap()));
+        assert!(config.http[0].listener.is_client_allowed("10.0.0.1".parse().unwrap()));
+        assert_eq!(config.http[0].listener.listen_addr().unwrap(), "0.0.0.0:8081".parse().unwrap());
+        assert_eq!(config.tcp[0].listener.listen_addr().unwrap(), "0.0.0.0:8082".parse().unwrap());
+        assert_eq!(config.tcp[0].proxy_protocol, None);
+        assert_eq!(config.tcp[1].proxy_protocol, Some(ProxyProtocolVersion::V2));
+        assert_eq!(config.metrics.as_ref().unwrap().listener.listen_addr().unwrap(), "127.0.0.1:9090".parse().unwrap());
+        assert_eq!(config.admin.as_ref().unwrap().unix_socket.as_deref(), Some("/run/http-tunnel/admin.sock".as_ref()));
+        assert_eq!(config.shutdown.drain_timeout, Duration::from_secs(60));
+        assert_eq!(config.reload.watch_interval, Some(Duration::from_secs(5)));
+        assert_eq!((config.bandwidth.total.upload, config.bandwidth.total.download), (None, Some(1000000)));
+        assert_eq!(config.acl.rules[0].ports.len(), 2);
+        let target_connection = &config.tunnel_config.target_connection;
+        assert_eq!(target_connection.address_family, AddressFamily::PreferIpv6);
+        assert_eq!(target_connection.connect_deadline, Some(Duration::from_secs(15)));
+        assert_eq!(target_connection.retry.max_attempts, 3);
+        assert_eq!(target_connection.retry.backoff, Duration::from_millis(200));
+        assert!(!target_connection.retry.failover);
+        let upstream = &config.tunnel_config.target_connection.upstream;
+        assert_eq!(upstream.len(), 2);
+        assert_eq!(upstream[0].username.as_deref(), Some("arthur"));
+        assert_eq!(upstream[0].protocol, UpstreamProtocol::Http);
+        assert!(upstream[0].remote_dns);
+        assert_eq!(upstream[1].protocol, UpstreamProtocol::Socks5);
+        assert!(!upstream[1].remote_dns);
+        assert!(upstream[1].password.is_none());
 
         info!("{:?}", config)
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_conf_parse_listener_array() {
+        let conf = r#"
+[[http]]
+name = "office"
+listen_port = 8081
+allowed_clients = ["192.168.0.0/16"]
+accept_proxy_protocol = "required"
+trusted_sources = ["10.0.0.0/24"]
+max_connections = 100
+max_connections_per_client = 10
+limit_queue_timeout = "2s"
+
+[http.auth]
+htpasswd = "/etc/http-tunnel/htpasswd"
+
+[[http]]
+name = "loopback"
+listen = "127.0.0.1:3128"
+
+[https]
+listen_port = 8443
+proxy_protocol = "v1"
+
+[https.bandwidth.per_client]
+upload = 50000
+
+[[https.routes]]
+sni = [".internal.example.com"]
+proxy_protocol = "v2"
+
+[[https.routes]]
+sni = ["public.example.com"]
+"#;
+
+        let config: Config = toml::from_str(conf).unwrap();
+        assert_eq!(config.http.len(), 2);
+        assert_eq!(config.shutdown.drain_timeout, Duration::from_secs(30));
+        assert!(config.reload.watch_interval.is_none());
+        assert_eq!(config.http[0].listener.name.as_deref(), Some("office"));
+        assert!(config.http[0].auth.is_some());
+        assert!(config.http[1].auth.is_none());
+        let office = &config.http[0].listener;
+        assert_eq!(office.accept_proxy_protocol, ProxyProtocolMode::Required);
+        assert!(office.is_trusted_source("::ffff:10.0.0.5".parse().unwrap()));
+        assert!(!office.is_trusted_source("192.168.1.1".parse().unwrap()));
+        // a header from anyone would let clients pick their own address
+        assert!(!config.http[1].listener.is_trusted_source("10.0.0.5".parse().unwrap()));
+        assert_eq!((office.max_connections, office.max_connections_per_client), (Some(100), Some(10)));
+        assert_eq!(office.limit_queue_timeout, Some(Duration::from_secs(2)));
+        assert!(config.http[1].listener.max_connection_rate_per_client.is_none());
+        assert_eq!(config.http[1].listener.accept_proxy_protocol, ProxyProtocolMode::None);
+        assert!(!config.http[1].forwarding.via);
+        assert_eq!(config.https.len(), 1);
+        assert!(config.https[0].listener.name.is_none());
+        assert_eq!(config.https[0].proxy_protocol, Some(ProxyProtocolVersion::V1));
+        assert_eq!(config.https[0].routes[0].proxy_protocol, Some(ProxyProtocolVersion::V2));
+        assert_eq!(config.https[0].routes[1].proxy_protocol, None);
+        assert_eq!(config.https[0].bandwidth.per_client.upload, Some(50000));
+        assert_eq!(config.http[0].bandwidth, Default::default());
+
+        let err = toml::from_str::<Config>("[http]\nlisten_port = \"x\"\n").unwrap_err();
+        assert!(err.to_string().contains("expected u16"), "{}", err);
+    }
+
+    #[test]
+    fn test_conf_parse_listen() {
+        let config: Config = toml::from_str("[http]\nlisten = \"127.0.0.1:8081\"\n").unwrap();
+        assert_eq!(config.http[0].listener.listen_addr().unwrap(), "127.0.0.1:8081".parse().unwrap());
+        let config: Config = toml::from_str("[http]\nlisten = \"[::1]:8081\"\nlisten_port = 8081\n").unwrap();
+        assert!(config.http[0].listener.listen_addr().is_err());
+        let config: Config = toml::from_str("[http]\n").unwrap();
+        assert!(config.http[0].listener.listen_addr().is_err());
+    }
+}
@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use prometheus::IntCounter;
+use serde::Serialize;
+use tokio::sync::Notify;
+use tokio::task::AbortHandle;
+
+use crate::metrics::METRICS;
+
+pub type AConnectionTable = Arc<ConnectionTable>;
+pub type AConnection = Arc<Connection>;
+
+/// Connections being served by all tunnels, listed and closed through the admin api and drained on shutdown.
+pub struct ConnectionTable {
+    next_id: AtomicU64,
+    connections: Mutex<HashMap<u64, AConnection>>,
+    /// notified whenever a connection ends
+    ended: Notify,
+}
+
+impl ConnectionTable {
+    pub fn new() -> Self {
+        Self { next_id: AtomicU64::new(1), connections: Mutex::new(HashMap::new()), ended: Notify::new() }
+    }
+
+    /// Adds a connection served by the task of `abort_handle`, it is listed until the returned guard is dropped.
+    pub fn register(self: &Arc<Self>, tunnel: &str, peer_addr: SocketAddr, abort_handle: AbortHandle) -> ConnectionGuard {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let conn = Arc::new(Connection::new(id, tunnel, peer_addr, abort_handle));
+        self.connections.lock().unwrap().insert(id, conn.clone());
+        ConnectionGuard { table: self.clone(), conn }
+    }
+
+    pub fn list(&self) -> Vec<AConnection> {
+        let mut conns = self.connections.lock().unwrap().values().cloned().collect::<Vec<_>>();
+        conns.sort_by_key(|it| it.id);
+        conns
+    }
+
+    pub fn len(&self) -> usize {
+        self.connections.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Waits until every connection ended.
+    pub async fn wait_empty(&self) {
+        loop {
+            // registered before checking, so a connection ending in between is not missed
+            let ended = self.ended.notified();
+            tokio::pin!(ended);
+            ended.as_mut().enable();
+            if self.is_empty() {
+                return;
+            }
+            ended.await;
+        }
+    }
+
+    /// Aborts the task serving connection `id`, both of its sockets are closed on drop.
+    pub fn close(&self, id: u64) -> bool {
+        let Some(conn) = self.connections.lock().unwrap().get(&id).cloned() else {
+            return false;
+        };
+        conn.abort_handle.abort();
+        true
+    }
+
+    pub fn close_all(&self) {
+        for conn in self.connections.lock().unwrap().values() {
+            conn.abort_handle.abort();
+        }
+    }
+}
+
+pub struct ConnectionGuard {
+    table: AConnectionTable,
+    conn: AConnection,
+}
+
+impl ConnectionGuard {
+    pub fn connection(&self) -> &AConnection {
+        &self.conn
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.table.connections.lock().unwrap().remove(&self.conn.id);
+        self.table.ended.notify_waiters();
+    }
+}
+
+pub struct Connection {
+    pub id: u64,
+    pub tunnel: String,
+    pub peer_addr: SocketAddr,
+    started_at: SystemTime,
+    details: Mutex<Details>,
+    bytes_up: AtomicU64,
+    bytes_down: AtomicU64,
+    metrics_bytes_up: IntCounter,
+    metrics_bytes_down: IntCounter,
+    abort_handle: AbortHandle,
+}
+
+/// Filled in by the handler as the handshake progresses.
+#[derive(Default)]
+struct Details {
+    client_addr: Option<SocketAddr>,
+    target: Option<String>,
+    host: Option<String>,
+}
+
+impl Connection {
+    fn new(id: u64, tunnel: &str, peer_addr: SocketAddr, abort_handle: AbortHandle) -> Self {
+        Self {
+            id,
+            tunnel: tunnel.to_string(),
+            peer_addr,
+            started_at: SystemTime::now(),
+            details: Mutex::new(Details::default()),
+            bytes_up: AtomicU64::new(0),
+            bytes_down: AtomicU64::new(0),
+            metrics_bytes_up: METRICS.bytes_up.with_label_values(&[tunnel]),
+            metrics_bytes_down: METRICS.bytes_down.with_label_values(&[tunnel]),
+            abort_handle,
+        }
+    }
+
+    /// Client address announced by a PROXY protocol header, when it differs from the peer.
+    pub fn set_client_addr(&self, client_addr: SocketAddr) {
+        self.details.lock().unwrap().client_addr = Some(client_addr);
+    }
+
+    /// `host:port` the connection is relayed to.
+    pub fn set_target(&self, target: String) {
+        self.details.lock().unwrap().target = Some(target);
+    }
+
+    /// SNI of https tunnels, Host of plain http requests.
+    pub fn set_host(&self, host: &str) {
+        self.details.lock().unwrap().host = Some(host.to_string());
+    }
+
+    /// Counts bytes from the client to the target (`up`) and back (`down`).
+    pub fn record_bytes(&self, up: u64, down: u64) {
+        self.bytes_up.fetch_add(up, Ordering::Relaxed);
+        self.bytes_down.fetch_add(down, Ordering::Relaxed);
+        self.metrics_bytes_up.inc_by(up);
+        self.metrics_bytes_down.inc_by(down);
+    }
+
+    pub fn snapshot(&self) -> ConnectionSnapshot {
+        let details = self.details.lock().unwrap();
+        ConnectionSnapshot {
+            id: self.id,
+            tunnel: self.tunnel.clone(),
+            client: details.client_addr.unwrap_or(self.peer_addr),
+            peer: self.peer_addr,
+            target: details.target.clone(),
+            host: details.host.clone(),
+            started_at: self.started_at.duration_since(UNIX_EPOCH).map(|it| it.as_secs()).unwrap_or_default(),
+            duration_secs: self.started_at.elapsed().map(|it| it.as_secs()).unwrap_or_default(),
+            bytes_up: self.bytes_up.load(Ordering::Relaxed),
+            bytes_down: self.bytes_down.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct ConnectionSnapshot {
+    pub id: u64,
+    pub tunnel: String,
+    pub client: SocketAddr,
+    pub peer: SocketAddr,
+    pub target: Option<String>,
+    pub host: Option<String>,
+    /// unix timestamp in seconds
+    pub started_at: u64,
+    pub duration_secs: u64,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_register_and_close() {
+        let table = Arc::new(ConnectionTable::new());
+        let (registered_tx, registered_rx) = tokio::sync::oneshot::channel::<ConnectionGuard>();
+        let task = tokio::spawn(async move {
+            let _guard = registered_rx.await;
+            std::future::pending::<()>().await
+        });
+        let guard = table.register("test_table", "127.0.0.1:50000".parse().unwrap(), task.abort_handle());
+        let conn = guard.connection().clone();
+        assert!(registered_tx.send(guard).is_ok());
+        conn.set_target("example.com:443".to_string());
+        conn.record_bytes(10, 20);
+
+        let snapshot = table.list()[0].snapshot();
+        assert_eq!(snapshot.target.as_deref(), Some("example.com:443"));
+        assert_eq!((snapshot.bytes_up, snapshot.bytes_down), (10, 20));
+
+        assert!(table.close(conn.id));
+        assert!(task.await.unwrap_err().is_cancelled());
+        assert!(table.list().is_empty());
+        assert!(!table.close(conn.id));
+    }
+}
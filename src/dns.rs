@@ -1,30 +1,86 @@
-use std::sync::Arc;
-use std::time::Duration;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+use hickory_resolver::config::LookupIpStrategy;
 use hickory_resolver::system_conf::read_system_conf;
 use hickory_resolver::TokioAsyncResolver;
+use serde::Serialize;
+
+use crate::conf::AddressFamily;
+use crate::metrics::{Metrics, METRICS};
 
 pub type TDNSResolver = Arc<DnsResolver>;
 pub struct DnsResolver {
     _inner: TokioAsyncResolver,
+    address_family: AddressFamily,
+    /// answers of the lookups made by this process, the resolver cache itself cannot be listed,
+    /// so entries may outlive or miss what it holds
+    records: Mutex<HashMap<String, (Vec<IpAddr>, Instant)>>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct RecentLookup {
+    pub host: String,
+    pub addrs: Vec<IpAddr>,
+    pub ttl_secs: u64,
 }
 
 impl DnsResolver {
-    pub fn new(dns_ttl: Option<Duration>) -> anyhow::Result<Self> {
+    pub fn new(dns_ttl: Option<Duration>, address_family: AddressFamily) -> anyhow::Result<Self> {
         let (sys_config, mut sys_options) = read_system_conf().map_err(|e| anyhow::anyhow!(e))?;
         if dns_ttl.is_some() {
             sys_options.positive_max_ttl = dns_ttl;
         }
+        sys_options.ip_strategy = match address_family {
+            AddressFamily::Ipv4Only => LookupIpStrategy::Ipv4Only,
+            AddressFamily::Ipv6Only => LookupIpStrategy::Ipv6Only,
+            AddressFamily::PreferIpv4 | AddressFamily::PreferIpv6 => LookupIpStrategy::Ipv4AndIpv6,
+        };
 
         let resolver = TokioAsyncResolver::tokio(sys_config, sys_options);
 
-        Ok(DnsResolver { _inner: resolver })
+        Ok(DnsResolver { _inner: resolver, address_family, records: Mutex::new(HashMap::new()) })
     }
 }
 
 impl DnsResolver {
-    pub async fn resolve(&self, host: &str) -> anyhow::Result<Vec<std::net::Ipv4Addr>> {
-        let addrs = self._inner.ipv4_lookup(host).await?;
-        Ok(addrs.iter().map(|it| it.0).collect::<Vec<_>>())
+    /// Resolves A and/or AAAA records, addresses of the preferred family come first.
+    pub async fn resolve(&self, host: &str) -> anyhow::Result<Vec<IpAddr>> {
+        let start = Instant::now();
+        let addrs = self._inner.lookup_ip(host).await;
+        Metrics::observe(&METRICS.dns_lookup_seconds, &addrs, start);
+        let addrs = addrs?;
+        let valid_until = addrs.valid_until();
+        let mut addrs = addrs.iter().collect::<Vec<_>>();
+        let prefer_ipv6 = self.address_family == AddressFamily::PreferIpv6;
+        addrs.sort_by_key(|it| it.is_ipv6() != prefer_ipv6);
+
+        let mut records = self.records.lock().unwrap();
+        let now = Instant::now();
+        records.retain(|_, (_, valid_until)| *valid_until > now);
+        records.insert(host.to_ascii_lowercase(), (addrs.clone(), valid_until));
+        Ok(addrs)
     }
-}
\ No newline at end of file
+
+    /// Hosts resolved recently by this process whose answers have not expired yet.
+    pub fn recent_lookups(&self) -> Vec<RecentLookup> {
+        let now = Instant::now();
+        let mut records = self.records.lock().unwrap().iter()
+            .filter(|(_, (_, valid_until))| *valid_until > now)
+            .map(|(host, (addrs, valid_until))| RecentLookup { host: host.clone(), addrs: addrs.clone(), ttl_secs: (*valid_until - now).as_secs() })
+            .collect::<Vec<_>>();
+        records.sort_by(|a, b| a.host.cmp(&b.host));
+        records
+    }
+
+    /// Clears the resolver cache and the recent lookups, returns how many hosts were listed.
+    pub fn flush_cache(&self) -> usize {
+        self._inner.clear_cache();
+        let mut records = self.records.lock().unwrap();
+        let flushed = records.len();
+        records.clear();
+        flushed
+    }
+}
@@ -1,10 +1,13 @@
-use std::io::BufRead;
+use std::net::Ipv6Addr;
 
 use anyhow::{anyhow, bail};
 use httparse::Status;
-use tokio_util::bytes::{Bytes, BytesMut};
+use tokio_util::bytes::{Buf, BytesMut};
 use tokio_util::codec::Decoder;
 
+use crate::metrics::METRICS;
+use crate::upstream::format_authority;
+
 pub struct HandshakeCodec {}
 
 impl HandshakeCodec {
@@ -16,34 +19,113 @@ impl HandshakeCodec {
 pub const INIT_HEADER_BUF_SIZE: usize = 4096;
 pub const MAX_HEADER_SIZE: usize = 1048575;
 
+/// Header names and raw values in received order.
+pub type Headers = Vec<(String, Vec<u8>)>;
+
 #[derive(Debug)]
 pub struct DecodeResult {
     pub is_connect: bool,
     pub host: String,
     pub port: u16,
     pub method: String,
-    pub header_len: usize,
-    pub req_body_bytes: Bytes,
+    /// `http` or `https` when the target was in absolute-form
+    pub scheme: Option<String>,
+    /// origin-form request target, `/path?query`, absolute-form targets are rewritten,
+    /// the `host:port` authority for CONNECT
+    pub path: String,
+    /// minor version, `1` for HTTP/1.1
+    pub version: u8,
+    /// `Host` always matches the request target authority
+    pub headers: Headers,
+    pub proxy_authorization: Option<String>,
 }
 
-fn extract_host_and_port(line: &str) -> anyhow::Result<(String, u16)> {
-    let mut path_header = line.split(":");
-    let mut host = path_header.next().unwrap_or("");
-    let mut port = match path_header.next() {
-        None => {
-            if host.starts_with("http") {
-                80
-            } else if host.starts_with("https") {
-                443
-            } else {
-                80
-            }
+impl DecodeResult {
+    /// Serializes the request head to forward to the origin server.
+    pub fn encode_head(&self) -> Vec<u8> {
+        let mut head = format!("{} {} HTTP/1.{}\r\n", self.method, self.path, self.version).into_bytes();
+        for (name, value) in &self.headers {
+            head.extend_from_slice(name.as_bytes());
+            head.extend_from_slice(b": ");
+            head.extend_from_slice(value);
+            head.extend_from_slice(b"\r\n");
         }
-        Some(port) => {
-            port.parse::<u16>().map_err(|_| anyhow!("port parse error"))?
+        head.extend_from_slice(b"\r\n");
+        head
+    }
+}
+
+/// Splits an absolute-form target like `http://user@example.com:8080/a?b` into
+/// the scheme, the authority `example.com:8080` and the origin-form `/a?b`.
+fn split_absolute_form(target: &str) -> Option<(&str, &str, String)> {
+    let (scheme, rest) = target.split_once("://")?;
+    if scheme.is_empty() || !scheme.bytes().all(|b| b.is_ascii_alphanumeric() || b"+-.".contains(&b)) {
+        return None;
+    }
+    let end = rest.find(['/', '?']).unwrap_or(rest.len());
+    let authority = &rest[..end];
+    let authority = authority.rsplit_once('@').map(|(_, it)| it).unwrap_or(authority);
+    let path = match &rest[end..] {
+        path if path.starts_with('/') => path.to_string(),
+        query => format!("/{}", query),
+    };
+    Some((scheme, authority, path))
+}
+
+/// Lowercases, drops the trailing dot and converts IDNs to punycode.
+fn normalize_host(host: &str) -> anyhow::Result<String> {
+    let trimmed = host.strip_suffix('.').unwrap_or(host);
+    let ascii = idna::domain_to_ascii(trimmed).map_err(|_| anyhow!("invalid host: {:?}", host))?;
+    if ascii.is_empty() || !ascii.bytes().all(|b| b.is_ascii_alphanumeric() || b"-._".contains(&b)) {
+        bail!("invalid host: {:?}", host);
+    }
+    Ok(ascii)
+}
+
+/// Parses a `host[:port]` authority, ipv6 literals are bracketed, the port is optional.
+pub fn parse_authority(authority: &str) -> anyhow::Result<(String, Option<u16>)> {
+    let (host, port) = match authority.strip_prefix('[') {
+        // ipv6 literal, e.g. `[::1]:443`
+        Some(rest) => {
+            let (host, rest) = rest.split_once(']').ok_or(anyhow!("invalid ipv6 host: {:?}", authority))?;
+            let ip = host.parse::<Ipv6Addr>().map_err(|_| anyhow!("invalid ipv6 host: {:?}", authority))?;
+            let port = match rest {
+                "" => None,
+                _ => Some(rest.strip_prefix(':').ok_or(anyhow!("invalid ipv6 host: {:?}", authority))?),
+            };
+            (ip.to_string(), port)
         }
+        None => match authority.split_once(':') {
+            Some((host, port)) => (normalize_host(host)?, Some(port)),
+            None => (normalize_host(authority)?, None),
+        },
+    };
+    let port = match port {
+        // an empty port means the default one, RFC 3986 section 3.2.3
+        None | Some("") => None,
+        Some(port) if port.bytes().all(|b| b.is_ascii_digit()) => match port.parse::<u16>() {
+            Ok(port) if port != 0 => Some(port),
+            _ => bail!("invalid port: {:?}", port),
+        },
+        Some(port) => bail!("invalid port: {:?}", port),
     };
-    Ok((host.to_string(), port))
+    Ok((host, port))
+}
+
+/// Host header value, port 80 when absent.
+fn extract_host_and_port(line: &str) -> anyhow::Result<(String, u16)> {
+    let (host, port) = parse_authority(line)?;
+    Ok((host, port.unwrap_or(80)))
+}
+
+fn find_header<'a>(headers: &[httparse::Header<'a>], name: &str) -> Option<&'a str> {
+    headers.iter().find_map(|header| {
+        if header.name.eq_ignore_ascii_case(name) {
+            std::str::from_utf8(header.value).ok()
+        } else {
+            None
+        }
+    })
 }
 
 impl Decoder for HandshakeCodec {
@@ -51,6 +133,16 @@ impl Decoder for HandshakeCodec {
     type Error = anyhow::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let result = self.decode_head(src);
+        if result.is_err() {
+            METRICS.handshake_errors.with_label_values(&["http"]).inc();
+        }
+        result
+    }
+}
+
+impl HandshakeCodec {
+    fn decode_head(&mut self, src: &mut BytesMut) -> anyhow::Result<Option<DecodeResult>> {
         if src.is_empty() {
             return Ok(None);
         }
@@ -58,28 +150,73 @@ impl Decoder for HandshakeCodec {
         let mut headers = [httparse::EMPTY_HEADER; 256];
         let mut request = httparse::Request::new(&mut headers);
 
-        return match request.parse(src)? {
+        match request.parse(src)? {
             Status::Complete(n) => {
                 let method = request.method.ok_or(anyhow::anyhow!("method not found"))?.to_string();
 
                 let is_connect = method.eq_ignore_ascii_case("CONNECT");
 
-                let mut host_header_line =
-                    request.headers.iter().find_map(|header| {
-                        if header.name.to_lowercase() == "host" {
-                            std::str::from_utf8(header.value).ok()
-                        } else {
-                            None
+                let target = request.path.ok_or(anyhow::anyhow!("path not found"))?;
+                let mut headers = request.headers.iter().map(|it| (it.name.to_string(), it.value.to_vec())).collect::<Headers>();
+
+                let host_headers = request.headers.iter().filter(|it| it.name.eq_ignore_ascii_case("host")).collect::<Vec<_>>();
+                if host_headers.len() > 1 {
+                    bail!("multiple host headers");
+                }
+                let host_header = match host_headers.first() {
+                    Some(header) => Some(std::str::from_utf8(header.value).map_err(|_| anyhow!("host not utf8"))?),
+                    None => None,
+                };
+
+                let (scheme, host, port, path) = if is_connect {
+                    // authority-form, the port is mandatory
+                    let (host, port) = parse_authority(target)?;
+                    let port = port.ok_or(anyhow!("CONNECT target without port: {:?}", target))?;
+                    if let Some(host_header) = host_header {
+                        let (header_host, header_port) = parse_authority(host_header)?;
+                        if header_host != host || header_port.is_some_and(|it| it != port) {
+                            bail!("host header {:?} conflicts with CONNECT target {:?}", host_header, target);
+                        }
+                    }
+                    (None, host, port, target.to_string())
+                } else if let Some((scheme, authority, path)) = split_absolute_form(target) {
+                    let scheme = scheme.to_ascii_lowercase();
+                    let default_port = match scheme.as_str() {
+                        "http" => 80,
+                        "https" => 443,
+                        _ => bail!("unsupported scheme: {:?}", scheme),
+                    };
+                    let (host, port) = parse_authority(authority)?;
+                    let port = port.unwrap_or(default_port);
+                    if let Some(host_header) = host_header {
+                        let (header_host, header_port) = parse_authority(host_header)?;
+                        if header_host != host || header_port.unwrap_or(default_port) != port {
+                            bail!("host header {:?} conflicts with request target {:?}", host_header, target);
                         }
-                    });
+                    }
+                    // RFC 9112 section 3.2.2, the authority of an absolute-form target replaces Host,
+                    // normalized like the host that is connected to
+                    let host_value = format_authority(&host, port);
+                    let host_value = host_value.strip_suffix(&format!(":{}", default_port)).unwrap_or(&host_value);
+                    let pos = headers.iter().position(|(k, _)| k.eq_ignore_ascii_case("host")).unwrap_or(0);
+                    headers.retain(|(k, _)| !k.eq_ignore_ascii_case("host"));
+                    headers.insert(pos.min(headers.len()), ("Host".to_string(), host_value.as_bytes().to_vec()));
+                    (Some(scheme), host, port, path)
+                } else {
+                    if !target.starts_with('/') && target != "*" {
+                        bail!("invalid request target: {:?}", target);
+                    }
+                    let (host, port) = extract_host_and_port(host_header.ok_or(anyhow!("host not found"))?)?;
+                    (None, host, port, target.to_string())
+                };
 
-                let host_header_line = host_header_line.ok_or(anyhow::anyhow!("host not found"))?;
+                let proxy_authorization = find_header(request.headers, "proxy-authorization").map(|it| it.to_string());
 
-                let (host, port) = extract_host_and_port(host_header_line)?;
+                let version = request.version.unwrap_or(1);
 
-                let req_body_bytes = src.split().freeze();
+                src.advance(n);
 
-                Ok(Some(DecodeResult { is_connect, host, port, method, header_len: n, req_body_bytes }))
+                Ok(Some(DecodeResult { is_connect, host, port, method, scheme, path, version, headers, proxy_authorization }))
             }
             Status::Partial => {
                 if src.len() >= MAX_HEADER_SIZE {
@@ -87,13 +224,17 @@ impl Decoder for HandshakeCodec {
                 }
                 Ok(None)
             }
-        };
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use log::info;
+    use tokio_util::bytes::BytesMut;
+    use tokio_util::codec::Decoder;
+
+    use super::*;
 
     #[test]
     fn test_parse() {
@@ -111,6 +252,68 @@ mod tests {
 
     #[test]
     fn test_parse_host_port() {
-        let str = "CONNECT edulyse.test.sewo.com:80 HTTP/1.1";
+        let mut src = BytesMut::from("CONNECT edulyse.test.sewo.com:80 HTTP/1.1\r\nHost: edulyse.test.sewo.com:80\r\nProxy-Authorization: Basic YTpi\r\n\r\n");
+        let result = HandshakeCodec::new().decode(&mut src).unwrap().unwrap();
+        assert!(result.is_connect);
+        assert_eq!(result.host, "edulyse.test.sewo.com");
+        assert_eq!(result.port, 80);
+        assert_eq!(result.proxy_authorization.as_deref(), Some("Basic YTpi"));
+    }
+
+    #[test]
+    fn test_extract_ipv6_host_and_port() -> anyhow::Result<()> {
+        assert_eq!(extract_host_and_port("[::1]:443")?, ("::1".to_string(), 443));
+        assert_eq!(extract_host_and_port("[2001:db8::1]")?, ("2001:db8::1".to_string(), 80));
+        assert!(extract_host_and_port("[::1]443").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_authority() -> anyhow::Result<()> {
+        assert_eq!(parse_authority("WWW.Example.COM.:8080")?, ("www.example.com".to_string(), Some(8080)));
+        assert_eq!(parse_authority("bücher.example")?, ("xn--bcher-kva.example".to_string(), None));
+        assert_eq!(parse_authority("[2001:DB8::1]:")?, ("2001:db8::1".to_string(), None));
+        assert_eq!(parse_authority("10.0.0.1:443")?, ("10.0.0.1".to_string(), Some(443)));
+        for invalid in ["", "example.com:0", "example.com:+80", "example.com:65536", "::1:80", "[::1", "a b.com", "a@b.com", "[example.com]:80"] {
+            assert!(parse_authority(invalid).is_err(), "{}", invalid);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_conflicting_host() {
+        let decode = |req: &str| HandshakeCodec::new().decode(&mut BytesMut::from(req));
+        assert!(decode("GET http://a.com/ HTTP/1.1\r\nHost: b.com\r\n\r\n").is_err());
+        assert!(decode("GET http://a.com/ HTTP/1.1\r\nHost: a.com:8080\r\n\r\n").is_err());
+        assert!(decode("GET / HTTP/1.1\r\nHost: a.com\r\nHost: b.com\r\n\r\n").is_err());
+        assert!(decode("CONNECT a.com:443 HTTP/1.1\r\nHost: b.com:443\r\n\r\n").is_err());
+        assert!(decode("CONNECT a.com HTTP/1.1\r\n\r\n").is_err());
+        assert!(decode("GET ftp://a.com/ HTTP/1.1\r\n\r\n").is_err());
+
+        let result = decode("CONNECT A.com:443 HTTP/1.1\r\nHost: a.com\r\n\r\n").unwrap().unwrap();
+        assert_eq!((result.host.as_str(), result.port, result.scheme), ("a.com", 443, None));
+        let result = decode("GET HTTPS://a.com/x HTTP/1.1\r\nHost: a.com:443\r\n\r\n").unwrap().unwrap();
+        assert_eq!((result.port, result.scheme.as_deref(), result.path.as_str()), (443, Some("https"), "/x"));
+    }
+
+    #[test]
+    fn test_absolute_form() -> anyhow::Result<()> {
+        let mut src = BytesMut::from("GET http://user@example.com:8080?q=1 HTTP/1.1\r\nAccept: */*\r\nHost: example.com:8080\r\n\r\nnext");
+        let result = HandshakeCodec::new().decode(&mut src)?.unwrap();
+        assert_eq!((result.host.as_str(), result.port), ("example.com", 8080));
+        assert_eq!(result.path, "/?q=1");
+        assert_eq!(result.encode_head(), b"GET /?q=1 HTTP/1.1\r\nAccept: */*\r\nHost: example.com:8080\r\n\r\n");
+        assert_eq!(&src[..], b"next");
+
+        let mut src = BytesMut::from("GET http://Bücher.Example.:80/ HTTP/1.1\r\n\r\n");
+        assert_eq!(HandshakeCodec::new().decode(&mut src)?.unwrap().encode_head(), b"GET / HTTP/1.1\r\nHost: xn--bcher-kva.example\r\n\r\n");
+        let mut src = BytesMut::from("GET http://[::1]:8080/ HTTP/1.1\r\nHost: [::1]:8080\r\n\r\n");
+        assert_eq!(HandshakeCodec::new().decode(&mut src)?.unwrap().encode_head(), b"GET / HTTP/1.1\r\nHost: [::1]:8080\r\n\r\n");
+
+        let mut src = BytesMut::from("GET /index.html HTTP/1.0\r\nHost: example.com\r\n\r\n");
+        let result = HandshakeCodec::new().decode(&mut src)?.unwrap();
+        assert_eq!(result.path, "/index.html");
+        assert_eq!(result.encode_head(), b"GET /index.html HTTP/1.0\r\nHost: example.com\r\n\r\n");
+        Ok(())
     }
 }
\ No newline at end of file
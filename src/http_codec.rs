@@ -0,0 +1,467 @@
+use std::net::IpAddr;
+
+use anyhow::{anyhow, bail};
+use httparse::Status;
+use tokio_util::bytes::{Buf, Bytes, BytesMut};
+use tokio_util::codec::Decoder;
+
+use crate::handshake_codec::{Headers, MAX_HEADER_SIZE};
+
+const MAX_CHUNK_LINE_SIZE: usize = 4096;
+
+/// How the end of a message body is found, RFC 9112 section 6.3.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyKind {
+    Empty,
+    Length(u64),
+    Chunked,
+    /// response without length, the body ends when the server closes the connection
+    UntilClose,
+}
+
+/// Returns all values of header `name`, matched case-insensitively.
+pub fn header_values<'a>(headers: &'a Headers, name: &'a str) -> impl Iterator<Item=&'a [u8]> + 'a {
+    headers.iter().filter(move |(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_slice())
+}
+
+/// Iterates the elements of comma separated list headers like `Connection` or `Transfer-Encoding`.
+fn list_values<'a>(headers: &'a Headers, name: &'a str) -> impl Iterator<Item=String> + 'a {
+    header_values(headers, name)
+        .flat_map(|v| v.split(|b| *b == b','))
+        .map(|v| String::from_utf8_lossy(v).trim().to_ascii_lowercase())
+        .filter(|v| !v.is_empty())
+}
+
+pub fn has_token(headers: &Headers, name: &str, token: &str) -> bool {
+    list_values(headers, name).any(|it| it == token)
+}
+
+/// Hop-by-hop fields of RFC 9110 section 7.6.1, `Transfer-Encoding` stays since
+/// bodies are relayed without re-encoding.
+const HOP_BY_HOP_HEADERS: &[&str] = &["connection", "proxy-connection", "keep-alive", "te", "upgrade", "proxy-authorization"];
+
+/// Fields the message framing and routing were already decided on, `Connection` must not remove them.
+const END_TO_END_HEADERS: &[&str] = &["content-length", "transfer-encoding", "host"];
+
+/// Removes hop-by-hop headers and the ones listed in `Connection`, returns the
+/// `Upgrade` value when the connection upgrade is asked for, to be passed on.
+/// Listing a framing field would let the next hop read the body differently, that is an error.
+pub fn strip_hop_by_hop(headers: &mut Headers) -> anyhow::Result<Option<Vec<u8>>> {
+    let listed = list_values(headers, "connection").collect::<Vec<_>>();
+    if let Some(name) = listed.iter().find(|it| END_TO_END_HEADERS.contains(&it.as_str())) {
+        bail!("connection header lists {}", name);
+    }
+    let upgrade = match listed.iter().any(|it| it == "upgrade") {
+        true => header_values(headers, "upgrade").next().map(|it| it.to_vec()),
+        false => None,
+    };
+    headers.retain(|(name, _)| {
+        let name = name.to_ascii_lowercase();
+        !HOP_BY_HOP_HEADERS.contains(&name.as_str()) && !listed.contains(&name)
+    });
+    Ok(upgrade)
+}
+
+/// Appends `value` to the last `name` field, list headers like `Via` may be combined this way.
+pub fn append_list_header(headers: &mut Headers, name: &str, value: &str) {
+    match headers.iter_mut().rev().find(|(k, _)| k.eq_ignore_ascii_case(name)) {
+        Some((_, existing)) => {
+            existing.extend_from_slice(b", ");
+            existing.extend_from_slice(value.as_bytes());
+        }
+        None => headers.push((name.to_string(), value.as_bytes().to_vec())),
+    }
+}
+
+/// RFC 7239 forwarded-element for a plain HTTP request from `client_ip`.
+pub fn forwarded_element(client_ip: IpAddr, host: &str) -> String {
+    let node = match client_ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("\"[{}]\"", ip),
+    };
+    let is_token = !host.is_empty() && host.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+    let host = if is_token { host.to_string() } else { format!("\"{}\"", host.replace(['"', '\\'], "")) };
+    format!("for={};host={};proto=http", node, host)
+}
+
+/// Whether the connection stays open after this message, HTTP/1.0 needs an explicit keep-alive.
+pub fn is_keep_alive(version: u8, headers: &Headers) -> bool {
+    if has_token(headers, "connection", "close") {
+        return false;
+    }
+    version >= 1 || has_token(headers, "connection", "keep-alive")
+}
+
+/// Whether repeating a request with this method has the same effect as sending it once (RFC 9110 9.2.2).
+pub fn is_idempotent(method: &str) -> bool {
+    ["GET", "HEAD", "OPTIONS", "TRACE", "PUT", "DELETE"].contains(&method)
+}
+
+/// All Content-Length fields must agree, `Content-Length: 5, 5` counts as one value.
+fn content_length(headers: &Headers) -> anyhow::Result<Option<u64>> {
+    let mut length = None;
+    for value in list_values(headers, "content-length") {
+        if !value.bytes().all(|b| b.is_ascii_digit()) {
+            bail!("invalid content-length: {}", value);
+        }
+        let value = value.parse::<u64>()?;
+        match length {
+            Some(prev) if prev != value => bail!("conflicting content-length: {} and {}", prev, value),
+            _ => length = Some(value),
+        }
+    }
+    Ok(length)
+}
+
+/// `None` without Transfer-Encoding, otherwise whether chunked is the final coding.
+fn is_chunked(headers: &Headers) -> anyhow::Result<Option<bool>> {
+    let codings = list_values(headers, "transfer-encoding").collect::<Vec<_>>();
+    if codings.is_empty() {
+        return Ok(None);
+    }
+    let chunked = codings.iter().filter(|it| *it == "chunked").count();
+    if chunked > 1 || (chunked == 1 && codings.last().map(String::as_str) != Some("chunked")) {
+        bail!("chunked must be applied once and last: {}", codings.join(", "));
+    }
+    Ok(Some(chunked == 1))
+}
+
+/// Request body framing, anything a front and back server could disagree on is an error.
+pub fn request_body_kind(version: u8, headers: &Headers) -> anyhow::Result<BodyKind> {
+    let length = content_length(headers)?;
+    match is_chunked(headers)? {
+        Some(_) if length.is_some() => bail!("both transfer-encoding and content-length present"),
+        Some(_) if version == 0 => bail!("transfer-encoding in a HTTP/1.0 request"),
+        Some(true) => Ok(BodyKind::Chunked),
+        Some(false) => bail!("request transfer-encoding not ending with chunked"),
+        None => match length {
+            Some(0) | None => Ok(BodyKind::Empty),
+            Some(n) => Ok(BodyKind::Length(n)),
+        },
+    }
+}
+
+/// Response body framing, depends on the request method and the status code.
+pub fn response_body_kind(request_method: &str, code: u16, headers: &Headers) -> anyhow::Result<BodyKind> {
+    if request_method.eq_ignore_ascii_case("HEAD") || (100..200).contains(&code) || code == 204 || code == 304 {
+        return Ok(BodyKind::Empty);
+    }
+    match is_chunked(headers)? {
+        Some(true) => Ok(BodyKind::Chunked),
+        Some(false) => Ok(BodyKind::UntilClose),
+        None => match content_length(headers)? {
+            Some(0) => Ok(BodyKind::Empty),
+            Some(n) => Ok(BodyKind::Length(n)),
+            None => Ok(BodyKind::UntilClose),
+        },
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum BodyChunk {
+    /// raw body bytes, chunked framing included, to be forwarded as is
+    Data(Bytes),
+    End,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ChunkedState {
+    Size,
+    Data(u64),
+    DataCrlf,
+    Trailer,
+    Done,
+}
+
+#[derive(Debug)]
+enum BodyState {
+    Length(u64),
+    Chunked(ChunkedState),
+    UntilClose,
+    Finished,
+}
+
+/// Splits a message body off the stream without altering it, so the
+/// connection can be reused for the next message.
+pub struct BodyCodec {
+    state: BodyState,
+}
+
+impl BodyCodec {
+    pub fn new(kind: BodyKind) -> Self {
+        let state = match kind {
+            BodyKind::Empty => BodyState::Length(0),
+            BodyKind::Length(n) => BodyState::Length(n),
+            BodyKind::Chunked => BodyState::Chunked(ChunkedState::Size),
+            BodyKind::UntilClose => BodyState::UntilClose,
+        };
+        Self { state }
+    }
+
+    fn decode_chunked(state: ChunkedState, src: &mut BytesMut) -> anyhow::Result<Option<(ChunkedState, Bytes)>> {
+        match state {
+            ChunkedState::Size | ChunkedState::Trailer => {
+                let Some(pos) = src.windows(2).position(|it| it == b"\r\n") else {
+                    if src.len() > MAX_CHUNK_LINE_SIZE {
+                        bail!("chunk line too long");
+                    }
+                    return Ok(None);
+                };
+                let line = src.split_to(pos + 2).freeze();
+                let next = if state == ChunkedState::Trailer {
+                    if pos == 0 { ChunkedState::Done } else { ChunkedState::Trailer }
+                } else {
+                    // chunk extensions after `;` are ignored
+                    let size = line[..pos].split(|b| *b == b';').next().unwrap_or_default();
+                    let size = std::str::from_utf8(size)?.trim_matches([' ', '\t']);
+                    if size.is_empty() || size.len() > 16 {
+                        bail!("invalid chunk size: {:?}", size);
+                    }
+                    match u64::from_str_radix(size, 16).map_err(|_| anyhow!("invalid chunk size: {:?}", size))? {
+                        0 => ChunkedState::Trailer,
+                        n => ChunkedState::Data(n),
+                    }
+                };
+                Ok(Some((next, line)))
+            }
+            ChunkedState::Data(remaining) => {
+                if src.is_empty() {
+                    return Ok(None);
+                }
+                let n = remaining.min(src.len() as u64);
+                let next = if n == remaining { ChunkedState::DataCrlf } else { ChunkedState::Data(remaining - n) };
+                Ok(Some((next, src.split_to(n as usize).freeze())))
+            }
+            ChunkedState::DataCrlf => {
+                if src.len() < 2 {
+                    return Ok(None);
+                }
+                if &src[..2] != b"\r\n" {
+                    bail!("missing CRLF after chunk data");
+                }
+                Ok(Some((ChunkedState::Size, src.split_to(2).freeze())))
+            }
+            ChunkedState::Done => Ok(None),
+        }
+    }
+}
+
+impl Decoder for BodyCodec {
+    type Item = BodyChunk;
+    type Error = anyhow::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self.state {
+            BodyState::Length(0) | BodyState::Chunked(ChunkedState::Done) => {
+                self.state = BodyState::Finished;
+                Ok(Some(BodyChunk::End))
+            }
+            BodyState::Length(remaining) => {
+                if src.is_empty() {
+                    return Ok(None);
+                }
+                let n = remaining.min(src.len() as u64);
+                self.state = BodyState::Length(remaining - n);
+                Ok(Some(BodyChunk::Data(src.split_to(n as usize).freeze())))
+            }
+            BodyState::Chunked(state) => match Self::decode_chunked(state, src)? {
+                Some((next, bytes)) => {
+                    self.state = BodyState::Chunked(next);
+                    Ok(Some(BodyChunk::Data(bytes)))
+                }
+                None => Ok(None),
+            },
+            BodyState::UntilClose => {
+                if src.is_empty() {
+                    return Ok(None);
+                }
+                Ok(Some(BodyChunk::Data(src.split().freeze())))
+            }
+            BodyState::Finished => Ok(None),
+        }
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if let Some(chunk) = self.decode(src)? {
+            return Ok(Some(chunk));
+        }
+        match self.state {
+            BodyState::UntilClose => {
+                self.state = BodyState::Finished;
+                Ok(Some(BodyChunk::End))
+            }
+            BodyState::Finished => Ok(None),
+            _ => bail!("connection closed in the middle of a message body"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ResponseHead {
+    pub version: u8,
+    pub code: u16,
+    pub reason: String,
+    pub headers: Headers,
+}
+
+impl ResponseHead {
+    pub fn encode_head(&self) -> Vec<u8> {
+        let mut head = format!("HTTP/1.{} {} {}\r\n", self.version, self.code, self.reason).into_bytes();
+        for (name, value) in &self.headers {
+            head.extend_from_slice(name.as_bytes());
+            head.extend_from_slice(b": ");
+            head.extend_from_slice(value);
+            head.extend_from_slice(b"\r\n");
+        }
+        head.extend_from_slice(b"\r\n");
+        head
+    }
+}
+
+pub struct ResponseHeadCodec {}
+
+impl ResponseHeadCodec {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl Decoder for ResponseHeadCodec {
+    type Item = ResponseHead;
+    type Error = anyhow::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if src.is_empty() {
+            return Ok(None);
+        }
+
+        let mut headers = [httparse::EMPTY_HEADER; 256];
+        let mut response = httparse::Response::new(&mut headers);
+
+        match response.parse(src)? {
+            Status::Complete(n) => {
+                let version = response.version.unwrap_or(1);
+                let code = response.code.ok_or(anyhow!("status code not found"))?;
+                let reason = response.reason.unwrap_or_default().to_string();
+                let headers = response.headers.iter().map(|it| (it.name.to_string(), it.value.to_vec())).collect();
+                src.advance(n);
+                Ok(Some(ResponseHead { version, code, reason, headers }))
+            }
+            Status::Partial => {
+                if src.len() >= MAX_HEADER_SIZE {
+                    bail!("response header too large")
+                }
+                Ok(None)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> Headers {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.as_bytes().to_vec())).collect()
+    }
+
+    fn decode_all(codec: &mut BodyCodec, src: &mut BytesMut) -> anyhow::Result<(Vec<u8>, bool)> {
+        let mut body = vec![];
+        while let Some(chunk) = codec.decode(src)? {
+            match chunk {
+                BodyChunk::Data(bytes) => body.extend_from_slice(&bytes),
+                BodyChunk::End => return Ok((body, true)),
+            }
+        }
+        Ok((body, false))
+    }
+
+    #[test]
+    fn test_request_body_kind() {
+        assert_eq!(request_body_kind(1, &headers(&[])).unwrap(), BodyKind::Empty);
+        assert_eq!(request_body_kind(1, &headers(&[("Content-Length", "5, 5")])).unwrap(), BodyKind::Length(5));
+        assert_eq!(request_body_kind(1, &headers(&[("Transfer-Encoding", "gzip, Chunked")])).unwrap(), BodyKind::Chunked);
+
+        assert!(request_body_kind(1, &headers(&[("Content-Length", "5"), ("Content-Length", "6")])).is_err());
+        assert!(request_body_kind(1, &headers(&[("Content-Length", "+5")])).is_err());
+        assert!(request_body_kind(1, &headers(&[("Content-Length", "5"), ("Transfer-Encoding", "chunked")])).is_err());
+        assert!(request_body_kind(1, &headers(&[("Transfer-Encoding", "chunked, gzip")])).is_err());
+        assert!(request_body_kind(1, &headers(&[("Transfer-Encoding", "chunked"), ("Transfer-Encoding", "chunked")])).is_err());
+        assert!(request_body_kind(0, &headers(&[("Transfer-Encoding", "chunked")])).is_err());
+    }
+
+    #[test]
+    fn test_response_body_kind() {
+        let chunked = headers(&[("Transfer-Encoding", "chunked")]);
+        assert_eq!(response_body_kind("GET", 200, &chunked).unwrap(), BodyKind::Chunked);
+        assert_eq!(response_body_kind("HEAD", 200, &chunked).unwrap(), BodyKind::Empty);
+        assert_eq!(response_body_kind("GET", 304, &headers(&[("Content-Length", "10")])).unwrap(), BodyKind::Empty);
+        assert_eq!(response_body_kind("GET", 200, &headers(&[])).unwrap(), BodyKind::UntilClose);
+    }
+
+    #[test]
+    fn test_is_idempotent() {
+        assert!(is_idempotent("GET") && is_idempotent("PUT") && is_idempotent("DELETE"));
+        // methods are case-sensitive
+        assert!(!is_idempotent("POST") && !is_idempotent("PATCH") && !is_idempotent("get"));
+    }
+
+    #[test]
+    fn test_strip_hop_by_hop() {
+        let mut h = headers(&[("Connection", "keep-alive, X-Secret, Upgrade"), ("X-Secret", "1"), ("Upgrade", "websocket"),
+            ("Proxy-Connection", "keep-alive"), ("Proxy-Authorization", "Basic YTpi"), ("Transfer-Encoding", "chunked"), ("Accept", "*/*")]);
+        assert_eq!(strip_hop_by_hop(&mut h).unwrap(), Some(b"websocket".to_vec()));
+        assert_eq!(h, headers(&[("Transfer-Encoding", "chunked"), ("Accept", "*/*")]));
+        for listed in ["close, Content-Length", "transfer-encoding", "Host"] {
+            assert!(strip_hop_by_hop(&mut headers(&[("Connection", listed), ("Content-Length", "5")])).is_err());
+        }
+
+        append_list_header(&mut h, "Via", "1.1 a");
+        append_list_header(&mut h, "via", "1.1 b");
+        assert_eq!(header_values(&h, "via").collect::<Vec<_>>(), vec![b"1.1 a, 1.1 b"]);
+    }
+
+    #[test]
+    fn test_forwarded_element() {
+        assert_eq!(forwarded_element("192.0.2.60".parse().unwrap(), "example.com"), "for=192.0.2.60;host=example.com;proto=http");
+        assert_eq!(forwarded_element("2001:db8::1".parse().unwrap(), "example.com:8080"), "for=\"[2001:db8::1]\";host=\"example.com:8080\";proto=http");
+    }
+
+    #[test]
+    fn test_decode_chunked() -> anyhow::Result<()> {
+        let raw = "4;ext=1\r\nWiki\r\n5\r\npedia\r\n0\r\nExpires: never\r\n\r\n";
+        let mut src = BytesMut::from(format!("{}GET / HTTP/1.1\r\n", raw).as_str());
+        let (body, finished) = decode_all(&mut BodyCodec::new(BodyKind::Chunked), &mut src)?;
+        assert!(finished);
+        assert_eq!(body, raw.as_bytes());
+        assert_eq!(&src[..], b"GET / HTTP/1.1\r\n");
+
+        let mut src = BytesMut::from("4\r\nWikiXX0\r\n\r\n");
+        assert!(decode_all(&mut BodyCodec::new(BodyKind::Chunked), &mut src).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_length() -> anyhow::Result<()> {
+        let mut codec = BodyCodec::new(BodyKind::Length(5));
+        let mut src = BytesMut::from("abc");
+        assert_eq!(decode_all(&mut codec, &mut src)?, (b"abc".to_vec(), false));
+        src.extend_from_slice(b"deGET");
+        assert_eq!(decode_all(&mut codec, &mut src)?, (b"de".to_vec(), true));
+        assert_eq!(&src[..], b"GET");
+        assert!(BodyCodec::new(BodyKind::Length(5)).decode_eof(&mut BytesMut::new()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_response_head() -> anyhow::Result<()> {
+        let mut src = BytesMut::from("HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok");
+        let mut codec = ResponseHeadCodec::new();
+        assert_eq!(codec.decode(&mut src)?.unwrap().code, 100);
+        let head = codec.decode(&mut src)?.unwrap();
+        assert_eq!(head.code, 200);
+        assert_eq!(response_body_kind("GET", head.code, &head.headers)?, BodyKind::Length(2));
+        assert_eq!(&src[..], b"ok");
+        Ok(())
+    }
+}
@@ -0,0 +1,174 @@
+use std::io::ErrorKind;
+
+use crate::conf::ErrorPageConfig;
+use crate::tcp_connector::ConnectError;
+
+/// Identifies this proxy in `Proxy-Status`, RFC 9209.
+const PROXY_NAME: &str = "http-tunnel-rs";
+const DEFAULT_TEMPLATE: &str = "{status} {reason}\n{error}: {details}\n";
+const DEFAULT_CONTENT_TYPE: &str = "text/plain; charset=utf-8";
+
+/// A request the proxy answers itself, `error` is a RFC 9209 proxy error type.
+#[derive(Debug, PartialEq)]
+pub struct ProxyError {
+    pub status: u16,
+    pub error: &'static str,
+    pub details: String,
+}
+
+impl ProxyError {
+    pub fn new(status: u16, error: &'static str, details: impl Into<String>) -> Self {
+        Self { status, error, details: details.into() }
+    }
+
+    /// Details are fixed per error type, resolver and address details only go to the log.
+    pub fn from_connect_error(err: &anyhow::Error) -> Self {
+        let (status, error, details) = match err.downcast_ref::<ConnectError>() {
+            Some(ConnectError::Resolve(_)) => (502, "dns_error", "dns lookup failed"),
+            Some(ConnectError::Timeout(_)) => (504, "connection_timeout", "connect timed out"),
+            Some(ConnectError::Io(err)) => match err.kind() {
+                ErrorKind::ConnectionRefused => (502, "connection_refused", "connection refused"),
+                ErrorKind::NetworkUnreachable | ErrorKind::HostUnreachable => (502, "destination_ip_unroutable", "destination unreachable"),
+                ErrorKind::TimedOut => (504, "connection_timeout", "connect timed out"),
+                _ => (502, "destination_unavailable", "connect failed"),
+            },
+            Some(ConnectError::Upstream(_)) => (502, "destination_unavailable", "upstream proxy failed"),
+            None => (502, "proxy_internal_error", "internal error"),
+        };
+        Self::new(status, error, details)
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            400 => "Bad Request",
+            403 => "Forbidden",
+            407 => "Proxy Authentication Required",
+            429 => "Too Many Requests",
+            502 => "Bad Gateway",
+            503 => "Service Unavailable",
+            504 => "Gateway Timeout",
+            _ => "Error",
+        }
+    }
+
+    fn proxy_status(&self) -> String {
+        format!("{}; error={}; details={}", PROXY_NAME, self.error, sf_string(&self.details))
+    }
+}
+
+/// Structured field string, RFC 8941 section 3.3.3, non printable characters are dropped.
+fn sf_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars().filter(|c| (' '..='~').contains(c)) {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn html_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '&' => escaped.push_str("&amp;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Renders error responses from the configured template or a short text default.
+///
+/// Placeholders: `{status}`, `{reason}`, `{error}`, `{details}` and `{host}`.
+pub struct ErrorPage {
+    template: String,
+    content_type: String,
+}
+
+impl ErrorPage {
+    pub fn load(config: Option<&ErrorPageConfig>) -> anyhow::Result<Self> {
+        match config {
+            Some(config) => Ok(Self { template: std::fs::read_to_string(&config.template)?, content_type: config.content_type.clone() }),
+            None => Ok(Self { template: DEFAULT_TEMPLATE.to_string(), content_type: DEFAULT_CONTENT_TYPE.to_string() }),
+        }
+    }
+
+    fn render_body(&self, err: &ProxyError, host: &str) -> String {
+        let html = self.content_type.contains("html");
+        let mut body = String::with_capacity(self.template.len());
+        let mut rest = self.template.as_str();
+        // single pass, so values containing placeholders are not expanded again
+        while let Some(start) = rest.find('{') {
+            body.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let Some(end) = rest.find('}') else {
+                break;
+            };
+            let value = match &rest[1..end] {
+                "status" => err.status.to_string(),
+                "reason" => err.reason().to_string(),
+                "error" => err.error.to_string(),
+                "details" => err.details.clone(),
+                "host" => host.to_string(),
+                _ => {
+                    body.push('{');
+                    rest = &rest[1..];
+                    continue;
+                }
+            };
+            body.push_str(&if html { html_escape(&value) } else { value });
+            rest = &rest[end + 1..];
+        }
+        body.push_str(rest);
+        body
+    }
+
+    /// Full response closing the connection, `extra_headers` are CRLF terminated lines.
+    pub fn render(&self, err: &ProxyError, host: &str, extra_headers: &str) -> Vec<u8> {
+        let body = self.render_body(err, host);
+        format!(
+            "HTTP/1.1 {} {}\r\nProxy-Status: {}\r\n{}Content-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            err.status, err.reason(), err.proxy_status(), extra_headers, self.content_type, body.len(), body
+        ).into_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_from_connect_error() {
+        let refused = anyhow::Error::from(ConnectError::Io(ErrorKind::ConnectionRefused.into()));
+        assert_eq!(ProxyError::from_connect_error(&refused).error, "connection_refused");
+        let timeout = anyhow::Error::from(ConnectError::Timeout(Duration::from_secs(10)));
+        assert_eq!(ProxyError::from_connect_error(&timeout).status, 504);
+        let resolve = anyhow::Error::from(ConnectError::Resolve(anyhow::anyhow!("no record found")));
+        assert_eq!(ProxyError::from_connect_error(&resolve).error, "dns_error");
+        assert_eq!(ProxyError::from_connect_error(&resolve).details, "dns lookup failed");
+    }
+
+    #[test]
+    fn test_render() {
+        let err = ProxyError::new(502, "connection_refused", "connect failed: \"refused\"");
+        let page = ErrorPage::load(None).unwrap();
+        let resp = String::from_utf8(page.render(&err, "example.com:80", "")).unwrap();
+        assert!(resp.starts_with("HTTP/1.1 502 Bad Gateway\r\n"));
+        assert!(resp.contains("Proxy-Status: http-tunnel-rs; error=connection_refused; details=\"connect failed: \\\"refused\\\"\"\r\n"));
+        assert!(resp.ends_with("\r\n\r\n502 Bad Gateway\nconnection_refused: connect failed: \"refused\"\n"));
+
+        let page = ErrorPage { template: "<p>{host} {details}</p>{unknown}".to_string(), content_type: "text/html".to_string() };
+        let err = ProxyError::new(403, "http_request_denied", "<{host}>");
+        assert_eq!(page.render_body(&err, "a&b"), "<p>a&amp;b &lt;{host}&gt;</p>{unknown}");
+    }
+}
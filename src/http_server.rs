@@ -0,0 +1,82 @@
+use std::future::Future;
+use std::sync::Arc;
+
+use anyhow::bail;
+use httparse::Status;
+use log::{error, info, warn};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::bytes::BytesMut;
+use tokio_util::sync::CancellationToken;
+
+use crate::conf::ListenerConfig;
+
+/// Requests to the built-in endpoints are small, anything larger is refused.
+const MAX_REQUEST_SIZE: usize = 16 * 1024;
+
+/// Request line of a request to one of the built-in endpoints, headers and body are not used.
+#[derive(Debug, PartialEq)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+}
+
+pub async fn read_request<R: AsyncRead + Unpin>(r: &mut R) -> anyhow::Result<Request> {
+    let mut buf = BytesMut::with_capacity(1024);
+    loop {
+        if r.read_buf(&mut buf).await? == 0 {
+            bail!("connection closed before the request was complete");
+        }
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut request = httparse::Request::new(&mut headers);
+        if let Status::Complete(_) = request.parse(&buf)? {
+            let method = request.method.unwrap_or_default().to_string();
+            let path = request.path.unwrap_or_default().to_string();
+            return Ok(Request { method, path });
+        }
+        if buf.len() >= MAX_REQUEST_SIZE {
+            bail!("request too large");
+        }
+    }
+}
+
+/// Writes a complete response and ends the connection, one request per connection.
+pub async fn write_response<W: AsyncWrite + Unpin>(w: &mut W, status: &str, content_type: &str, body: &[u8]) -> anyhow::Result<()> {
+    let head = format!("HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", status, content_type, body.len());
+    w.write_all(head.as_bytes()).await?;
+    w.write_all(body).await?;
+    w.shutdown().await?;
+    Ok(())
+}
+
+/// Accepts connections to a built-in endpoint until `shutdown` is cancelled, each is answered by `handle`.
+/// They are not tunneled, so neither counted in the tunnel metrics nor listed as connections,
+/// of the listener settings only `allowed_clients` applies.
+pub async fn serve<H, F>(name: &str, listener_config: &ListenerConfig, listener: Arc<TcpListener>, shutdown: CancellationToken, handle: H) -> anyhow::Result<()>
+where
+    H: Fn(TcpStream) -> F,
+    F: Future<Output = anyhow::Result<()>> + Send + 'static,
+{
+    let bind_addr = listener.local_addr()?;
+    info!("[{}] listening on: {}", name, bind_addr);
+    loop {
+        let (stream, peer_addr) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = shutdown.cancelled() => {
+                info!("[{}] stopped listening on: {}", name, bind_addr);
+                return Ok(());
+            }
+        };
+        if !listener_config.is_client_allowed(peer_addr.ip()) {
+            warn!("[{}] rejected connection from {}, not in allowed_clients", name, peer_addr);
+            continue;
+        }
+        let request = handle(stream);
+        let name = name.to_string();
+        tokio::spawn(async move {
+            if let Err(e) = request.await {
+                error!("[{}] {} process connection error: {:?}", name, peer_addr, e);
+            }
+        });
+    }
+}
@@ -0,0 +1,361 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::Notify;
+use tokio::time::{Instant, timeout_at};
+
+use crate::conf::ListenerConfig;
+
+/// Rate buckets of clients idle long enough to be full again are dropped past this many.
+const MAX_IDLE_BUCKETS: usize = 4096;
+/// Connections of one listener waiting for a slot, further ones are refused right away.
+const MAX_QUEUED: usize = 1024;
+
+pub type AConnectionLimiter = Arc<ConnectionLimiter>;
+
+/// Refills at `rate` per second up to `capacity`.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    /// Starts full, so a burst of `capacity` is allowed right away.
+    pub fn new(rate: f64, capacity: f64) -> Self {
+        Self { rate, capacity, tokens: capacity, refilled_at: Instant::now() }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.refilled_at).as_secs_f64() * self.rate).min(self.capacity);
+        self.refilled_at = now;
+    }
+
+    /// How long until `n` tokens are available, zero when they are.
+    pub fn wait_time(&mut self, n: f64) -> Duration {
+        self.refill();
+        match self.tokens >= n {
+            true => Duration::ZERO,
+            false => Duration::from_secs_f64((n - self.tokens) / self.rate),
+        }
+    }
+
+    /// Takes `n` tokens, the balance may go negative and is paid back by later callers waiting longer.
+    pub fn take(&mut self, n: f64) {
+        self.refill();
+        self.tokens -= n;
+    }
+
+    pub fn capacity(&self) -> f64 {
+        self.capacity
+    }
+
+    fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.capacity
+    }
+}
+
+/// Which limit refused a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitExceeded {
+    /// `max_connections` of the listener
+    Connections,
+    /// `max_connections_per_client`
+    ClientConnections,
+    /// `max_connection_rate_per_client`
+    ClientRate,
+}
+
+impl LimitExceeded {
+    /// Listener wide limits are the proxy's problem (503), per client ones the client's (429).
+    pub fn http_status(&self) -> u16 {
+        match self {
+            LimitExceeded::Connections => 503,
+            LimitExceeded::ClientConnections | LimitExceeded::ClientRate => 429,
+        }
+    }
+
+    /// `reason` label of the failed connections metric.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            LimitExceeded::Connections => "limit_connections",
+            LimitExceeded::ClientConnections => "limit_client_connections",
+            LimitExceeded::ClientRate => "limit_client_rate",
+        }
+    }
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitExceeded::Connections => write!(f, "max_connections reached"),
+            LimitExceeded::ClientConnections => write!(f, "max_connections_per_client reached"),
+            LimitExceeded::ClientRate => write!(f, "max_connection_rate_per_client exceeded"),
+        }
+    }
+}
+
+/// Connection limits of one listener, kept across reloads so the connections it serves stay counted.
+///
+/// A connection over a limit waits up to `limit_queue_timeout` for a slot, without one or with
+/// `MAX_QUEUED` connections waiting already it is refused right away.
+pub struct ConnectionLimiter {
+    state: Mutex<LimiterState>,
+    released: Notify,
+}
+
+#[derive(Default)]
+struct LimiterState {
+    max_connections: Option<usize>,
+    max_per_client: Option<usize>,
+    rate_per_client: Option<u32>,
+    queue_timeout: Duration,
+    queued: usize,
+    active: usize,
+    active_per_client: HashMap<IpAddr, usize>,
+    rates: HashMap<IpAddr, TokenBucket>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(listener_config: &ListenerConfig) -> Self {
+        let limiter = Self { state: Mutex::new(LimiterState::default()), released: Notify::new() };
+        limiter.configure(listener_config);
+        limiter
+    }
+
+    /// Applies the limits of a reloaded listener, connections already served stay counted.
+    pub fn configure(&self, listener_config: &ListenerConfig) {
+        let mut state = self.state.lock().unwrap();
+        state.max_connections = listener_config.max_connections;
+        state.max_per_client = listener_config.max_connections_per_client;
+        let rate_per_client = listener_config.max_connection_rate_per_client.filter(|it| *it > 0);
+        if rate_per_client != state.rate_per_client {
+            state.rates.clear();
+        }
+        state.rate_per_client = rate_per_client;
+        state.queue_timeout = listener_config.limit_queue_timeout.unwrap_or_default();
+        drop(state);
+        // raised limits may let queued connections in
+        self.released.notify_waiters();
+    }
+
+    /// Waits for a slot of `max_connections`, it is held until the returned permit is dropped.
+    /// Checked once the client passed `allowed_clients`, the per client limits follow.
+    pub async fn acquire(self: &Arc<Self>) -> Result<ConnectionPermit, LimitExceeded> {
+        self.wait_for(|| {
+            let mut state = self.state.lock().unwrap();
+            if state.max_connections.is_some_and(|max| state.active >= max) {
+                return Err((LimitExceeded::Connections, None));
+            }
+            state.active += 1;
+            Ok(ConnectionPermit { limiter: self.clone(), client: None })
+        }).await
+    }
+
+    /// Retries `attempt` as slots are released or, for the rate limit, when it asks to, until the queue timeout.
+    async fn wait_for<T>(&self, mut attempt: impl FnMut() -> Result<T, (LimitExceeded, Option<Duration>)>) -> Result<T, LimitExceeded> {
+        let deadline = Instant::now() + self.state.lock().unwrap().queue_timeout;
+        let mut queued = None;
+        loop {
+            // registered before checking, so a release in between is not missed
+            let released = self.released.notified();
+            tokio::pin!(released);
+            released.as_mut().enable();
+            let (limit, retry_in) = match attempt() {
+                Ok(acquired) => return Ok(acquired),
+                Err(exceeded) => exceeded,
+            };
+            if queued.is_none() {
+                queued = Some(self.enqueue().ok_or(limit)?);
+            }
+            let woken = async {
+                match retry_in {
+                    Some(retry_in) => tokio::time::sleep(retry_in).await,
+                    None => released.await,
+                }
+            };
+            if timeout_at(deadline, woken).await.is_err() {
+                return Err(limit);
+            }
+        }
+    }
+
+    /// Counts a waiting connection until the guard is dropped, `None` when it may not wait.
+    fn enqueue(&self) -> Option<QueuedGuard<'_>> {
+        let mut state = self.state.lock().unwrap();
+        if state.queue_timeout.is_zero() || state.queued >= MAX_QUEUED {
+            return None;
+        }
+        state.queued += 1;
+        Some(QueuedGuard(self))
+    }
+
+    /// The per client limit hit and, for the rate limit, when to try again.
+    fn try_acquire_client(&self, client: IpAddr) -> Result<bool, (LimitExceeded, Option<Duration>)> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        if state.max_per_client.is_some_and(|max| state.active_per_client.get(&client).copied().unwrap_or_default() >= max) {
+            return Err((LimitExceeded::ClientConnections, None));
+        }
+        if let Some(rate) = state.rate_per_client {
+            if state.rates.len() >= MAX_IDLE_BUCKETS {
+                state.rates.retain(|_, bucket| !bucket.is_full());
+            }
+            let bucket = state.rates.entry(client).or_insert_with(|| TokenBucket::new(rate as f64, rate as f64));
+            let wait = bucket.wait_time(1.0);
+            if !wait.is_zero() {
+                return Err((LimitExceeded::ClientRate, Some(wait)));
+            }
+            bucket.take(1.0);
+        }
+        if state.max_per_client.is_none() {
+            return Ok(false);
+        }
+        *state.active_per_client.entry(client).or_default() += 1;
+        Ok(true)
+    }
+}
+
+struct QueuedGuard<'a>(&'a ConnectionLimiter);
+
+impl Drop for QueuedGuard<'_> {
+    fn drop(&mut self) {
+        self.0.state.lock().unwrap().queued -= 1;
+    }
+}
+
+pub struct ConnectionPermit {
+    limiter: AConnectionLimiter,
+    /// set once the connection counts against its client
+    client: Option<IpAddr>,
+}
+
+impl ConnectionPermit {
+    /// Waits for a slot of the per client limits, it is held along with the listener wide one.
+    pub async fn acquire_client(&mut self, client: IpAddr) -> Result<(), LimitExceeded> {
+        let client = client.to_canonical();
+        let counted = self.limiter.wait_for(|| self.limiter.try_acquire_client(client)).await?;
+        self.client = counted.then_some(client);
+        Ok(())
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let mut state = self.limiter.state.lock().unwrap();
+        state.active -= 1;
+        if let Some(client) = self.client {
+            if let Some(count) = state.active_per_client.get_mut(&client) {
+                *count -= 1;
+                if *count == 0 {
+                    state.active_per_client.remove(&client);
+                }
+            }
+        }
+        drop(state);
+        self.limiter.released.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_limiter(conf: &str) -> AConnectionLimiter {
+        let listener_config: ListenerConfig = toml::from_str(conf).unwrap();
+        Arc::new(ConnectionLimiter::new(&listener_config))
+    }
+
+    /// Both checks, as a connection from `client` goes through them.
+    async fn acquire(limiter: &AConnectionLimiter, client: IpAddr) -> Result<ConnectionPermit, LimitExceeded> {
+        let mut permit = limiter.acquire().await?;
+        permit.acquire_client(client).await?;
+        Ok(permit)
+    }
+
+    #[tokio::test]
+    async fn test_connection_limits() {
+        let limiter = new_limiter(r#"
+listen_port = 0
+max_connections = 3
+max_connections_per_client = 2
+"#);
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+        let a1 = acquire(&limiter, a).await.unwrap();
+        let _a2 = acquire(&limiter, a).await.unwrap();
+        assert_eq!(acquire(&limiter, a).await.err(), Some(LimitExceeded::ClientConnections));
+        let _b1 = acquire(&limiter, b).await.unwrap();
+        assert_eq!(acquire(&limiter, b).await.err(), Some(LimitExceeded::Connections));
+        drop(a1);
+        assert!(acquire(&limiter, b).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_reconfigure() {
+        let limiter = new_limiter(r#"
+listen_port = 0
+max_connections = 1
+"#);
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let _held = acquire(&limiter, a).await.unwrap();
+        limiter.configure(&toml::from_str(r#"
+listen_port = 0
+max_connections = 2
+"#).unwrap());
+        // the connection served before the reload still counts
+        let _second = acquire(&limiter, a).await.unwrap();
+        assert_eq!(acquire(&limiter, a).await.err(), Some(LimitExceeded::Connections));
+    }
+
+    #[tokio::test]
+    async fn test_queue_and_rate() {
+        let limiter = new_limiter(r#"
+listen_port = 0
+max_connections = 1
+limit_queue_timeout = "1s"
+"#);
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let held = acquire(&limiter, a).await.unwrap();
+        let queued = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { acquire(&limiter, a).await.map(|_| ()) }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        drop(held);
+        assert_eq!(queued.await.unwrap(), Ok(()));
+
+        // the queue is full, nothing waits for its timeout
+        let held = acquire(&limiter, a).await.unwrap();
+        let mut queued = vec![];
+        for _ in 0..MAX_QUEUED {
+            let limiter = limiter.clone();
+            queued.push(tokio::spawn(async move { acquire(&limiter, a).await.map(|_| ()) }));
+        }
+        while limiter.state.lock().unwrap().queued < MAX_QUEUED {
+            tokio::task::yield_now().await;
+        }
+        let start = Instant::now();
+        assert_eq!(acquire(&limiter, a).await.err(), Some(LimitExceeded::Connections));
+        assert!(start.elapsed() < Duration::from_millis(100), "{:?}", start.elapsed());
+        drop(held);
+        for queued in queued {
+            assert_eq!(queued.await.unwrap(), Ok(()));
+        }
+        assert_eq!(limiter.state.lock().unwrap().queued, 0);
+
+        let limiter = new_limiter(r#"
+listen_port = 0
+max_connection_rate_per_client = 1
+"#);
+        let _first = acquire(&limiter, a).await.unwrap();
+        assert_eq!(acquire(&limiter, a).await.err(), Some(LimitExceeded::ClientRate));
+    }
+}
@@ -0,0 +1,326 @@
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use anyhow::bail;
+use log::info;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::admin::{AdminServer, AUnixSocket, UnixSocket};
+use crate::conf::{AdminConfig, Config, HttpConfig, HttpsConfig, ListenerConfig, MetricsConfig, Socks4Config, Socks5Config, TcpConfig};
+use crate::connection_handle::{ATunnelContext, bind_listener, HttpsTunnel, HttpTunnel, serve, Socks4Tunnel, Socks5Tunnel, TcpTunnel};
+use crate::limits::{AConnectionLimiter, ConnectionLimiter};
+use crate::metrics::MetricsServer;
+use crate::tcp_connector::split_host_port;
+
+type ServeFuture = Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send>>;
+
+/// Bound socket of a listener, handed over to its replacement when the config changes.
+#[derive(Clone)]
+enum Socket {
+    Tcp(Arc<TcpListener>),
+    Unix(AUnixSocket),
+}
+
+/// One listener of the config, compared across reloads to find the ones to restart.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ListenerSpec {
+    Http(HttpConfig),
+    Https(HttpsConfig),
+    Socks5(Socks5Config),
+    Socks4(Socks4Config),
+    Tcp(TcpConfig),
+    Metrics(MetricsConfig),
+    Admin(AdminConfig),
+}
+
+impl ListenerSpec {
+    pub fn from_config(config: &Config) -> Vec<ListenerSpec> {
+        let mut specs = vec![];
+        specs.extend(config.http.iter().cloned().map(ListenerSpec::Http));
+        specs.extend(config.https.iter().cloned().map(ListenerSpec::Https));
+        specs.extend(config.socks5.iter().cloned().map(ListenerSpec::Socks5));
+        specs.extend(config.socks4.iter().cloned().map(ListenerSpec::Socks4));
+        specs.extend(config.tcp.iter().cloned().map(ListenerSpec::Tcp));
+        specs.extend(config.metrics.iter().cloned().map(ListenerSpec::Metrics));
+        specs.extend(config.admin.iter().cloned().map(ListenerSpec::Admin));
+        specs
+    }
+
+    fn listener_config(&self) -> &ListenerConfig {
+        match self {
+            ListenerSpec::Http(conf) => &conf.listener,
+            ListenerSpec::Https(conf) => &conf.listener,
+            ListenerSpec::Socks5(conf) => &conf.listener,
+            ListenerSpec::Socks4(conf) => &conf.listener,
+            ListenerSpec::Tcp(conf) => &conf.listener,
+            ListenerSpec::Metrics(conf) => &conf.listener,
+            ListenerSpec::Admin(conf) => &conf.listener,
+        }
+    }
+
+    /// Address or unix socket path, no two listeners may share one and a listener with the same key
+    /// is replaced instead of started next to it.
+    fn key(&self) -> anyhow::Result<String> {
+        if let ListenerSpec::Admin(AdminConfig { unix_socket: Some(path), .. }) = self {
+            return Ok(path.display().to_string());
+        }
+        Ok(self.listener_config().listen_addr()?.to_string())
+    }
+
+    fn bind(&self) -> anyhow::Result<Socket> {
+        if let ListenerSpec::Admin(AdminConfig { unix_socket: Some(path), .. }) = self {
+            return Ok(Socket::Unix(Arc::new(UnixSocket::bind(path)?)));
+        }
+        Ok(Socket::Tcp(Arc::new(bind_listener(self.listener_config().listen_addr()?)?)))
+    }
+
+    /// Builds the handler, so invalid settings are reported before anything is replaced.
+    fn build(&self, ctx: &ATunnelContext, socket: Socket, limiter: AConnectionLimiter, stop: CancellationToken) -> anyhow::Result<ServeFuture> {
+        let connections = ctx.connections.clone();
+        let listener = match socket {
+            Socket::Tcp(listener) => listener,
+            Socket::Unix(socket) => match self {
+                ListenerSpec::Admin(conf) => return Ok(Box::pin(Arc::new(AdminServer::new(conf.clone(), ctx.clone())?).serve_unix(socket, stop))),
+                _ => unreachable!("only the admin api listens on a unix socket"),
+            },
+        };
+        let serve_future: ServeFuture = match self {
+            ListenerSpec::Http(conf) => Box::pin(serve(Arc::new(HttpTunnel::new(conf.clone(), ctx.clone())?), listener, limiter, connections, stop)),
+            ListenerSpec::Https(conf) => Box::pin(serve(Arc::new(HttpsTunnel::new(conf.clone(), ctx.clone())), listener, limiter, connections, stop)),
+            ListenerSpec::Socks5(conf) => Box::pin(serve(Arc::new(Socks5Tunnel::new(conf.clone(), ctx.clone())?), listener, limiter, connections, stop)),
+            ListenerSpec::Socks4(conf) => Box::pin(serve(Arc::new(Socks4Tunnel::new(conf.clone(), ctx.clone())), listener, limiter, connections, stop)),
+            ListenerSpec::Tcp(conf) => {
+                split_host_port(&conf.remote_addr)?;
+                Box::pin(serve(Arc::new(TcpTunnel::new(conf.clone(), ctx.clone())), listener, limiter, connections, stop))
+            }
+            ListenerSpec::Metrics(conf) => Box::pin(Arc::new(MetricsServer::new(conf.clone())).serve(listener, stop)),
+            ListenerSpec::Admin(conf) => Box::pin(Arc::new(AdminServer::new(conf.clone(), ctx.clone())?).serve(listener, stop)),
+        };
+        Ok(serve_future)
+    }
+}
+
+/// Listeners to start and stop to get from the running set to a new config.
+pub struct Plan {
+    start: Vec<Starting>,
+    stop: Vec<String>,
+    unchanged: usize,
+}
+
+/// Listener of a plan, bound and built but not accepting yet.
+struct Starting {
+    key: String,
+    spec: ListenerSpec,
+    socket: Socket,
+    limiter: AConnectionLimiter,
+    stop: CancellationToken,
+    serve_future: ServeFuture,
+}
+
+struct Running {
+    id: u64,
+    spec: ListenerSpec,
+    socket: Socket,
+    limiter: AConnectionLimiter,
+    stop: CancellationToken,
+    task: JoinHandle<()>,
+}
+
+/// Running listeners, each stops on its own token or on shutdown.
+pub struct Listeners {
+    ctx: ATunnelContext,
+    running: HashMap<String, Running>,
+    next_id: u64,
+    stopped_tx: mpsc::UnboundedSender<(u64, anyhow::Result<()>)>,
+    stopped_rx: mpsc::UnboundedReceiver<(u64, anyhow::Result<()>)>,
+}
+
+impl Listeners {
+    pub fn new(ctx: ATunnelContext) -> Self {
+        let (stopped_tx, stopped_rx) = mpsc::unbounded_channel();
+        Self { ctx, running: HashMap::new(), next_id: 0, stopped_tx, stopped_rx }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.running.is_empty()
+    }
+
+    /// Validates `specs` against the running listeners and binds the new addresses, nothing running is changed yet.
+    /// Unchanged listeners keep running, changed ones take over the socket of the one they replace.
+    pub fn prepare(&self, specs: Vec<ListenerSpec>) -> anyhow::Result<Plan> {
+        let mut plan = Plan { start: vec![], stop: vec![], unchanged: 0 };
+        let mut keys = HashSet::new();
+        for spec in specs {
+            let key = spec.key()?;
+            if !keys.insert(key.clone()) {
+                bail!("{} is configured more than once", key);
+            }
+            match self.running.get(&key) {
+                Some(running) if running.spec == spec => plan.unchanged += 1,
+                Some(running) => {
+                    plan.stop.push(key.clone());
+                    plan.start.push(self.build(key, spec, running.socket.clone(), running.limiter.clone())?);
+                }
+                None => {
+                    let socket = spec.bind().map_err(|e| e.context(format!("failed to bind listener {}", key)))?;
+                    let limiter = Arc::new(ConnectionLimiter::new(spec.listener_config()));
+                    plan.start.push(self.build(key, spec, socket, limiter)?);
+                }
+            }
+        }
+        plan.stop.extend(self.running.keys().filter(|it| !keys.contains(*it)).cloned());
+        Ok(plan)
+    }
+
+    fn build(&self, key: String, spec: ListenerSpec, socket: Socket, limiter: AConnectionLimiter) -> anyhow::Result<Starting> {
+        let stop = self.ctx.shutdown.child_token();
+        let serve_future = spec.build(&self.ctx, socket.clone(), limiter.clone(), stop.clone()).map_err(|e| e.context(format!("invalid listener {}", key)))?;
+        Ok(Starting { key, spec, socket, limiter, stop, serve_future })
+    }
+
+    /// Stops listeners first, so a changed one does not accept next to its replacement, then starts the new ones.
+    pub async fn apply(&mut self, plan: Plan) {
+        for key in &plan.stop {
+            if let Some(running) = self.running.remove(key) {
+                running.stop.cancel();
+                let _ = running.task.await;
+            }
+        }
+        let started = plan.start.len();
+        for Starting { key, spec, socket, limiter, stop, serve_future } in plan.start {
+            // the limiter of a replaced listener keeps counting its connections with the new limits
+            limiter.configure(spec.listener_config());
+            self.next_id += 1;
+            let id = self.next_id;
+            let stopped_tx = self.stopped_tx.clone();
+            let task = tokio::spawn(async move {
+                let result = serve_future.await;
+                let _ = stopped_tx.send((id, result));
+            });
+            self.running.insert(key, Running { id, spec, socket, limiter, stop, task });
+        }
+        info!("listeners started: {}, stopped: {}, unchanged: {}", started, plan.stop.len(), plan.unchanged);
+    }
+
+    /// Waits for a listener that ended by itself, e.g. when its address is in use.
+    pub async fn next_stopped(&mut self) -> (String, anyhow::Result<()>) {
+        loop {
+            let (id, result) = self.stopped_rx.recv().await.expect("sender is held by self");
+            // listeners stopped by `apply` are already gone
+            let Some(key) = self.running.iter().find(|(_, it)| it.id == id).map(|(key, _)| key.clone()) else {
+                continue;
+            };
+            self.running.remove(&key);
+            return (key, result);
+        }
+    }
+
+    /// Waits for every listener after shutdown was cancelled.
+    pub async fn join(&mut self) {
+        for (_, running) in self.running.drain() {
+            let _ = running.task.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::acl::Acl;
+    use crate::bandwidth::BandwidthLimits;
+    use crate::connection_handle::TunnelContext;
+    use crate::tcp_connector::TcpConnector;
+
+    use super::*;
+
+    fn specs(conf: &str) -> Vec<ListenerSpec> {
+        ListenerSpec::from_config(&toml::from_str(conf).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_reload_plan() {
+        let tcp_connector = Arc::new(TcpConnector::new(Default::default()).unwrap());
+        let acl = Arc::new(Acl::new(&Default::default()).unwrap());
+        let ctx = Arc::new(TunnelContext::new(tcp_connector, acl, Arc::new(BandwidthLimits::new(&Default::default()))));
+        let mut listeners = Listeners::new(ctx.clone());
+
+        let plan = listeners.prepare(specs(r#"
+[[tcp]]
+listen = "127.0.0.1:0"
+remote_addr = "127.0.0.1:22"
+[[tcp]]
+listen = "127.0.0.1:18422"
+remote_addr = "127.0.0.1:80"
+"#)).unwrap();
+        assert_eq!((plan.start.len(), plan.stop.len()), (2, 0));
+        listeners.apply(plan).await;
+
+        let plan = listeners.prepare(specs(r#"
+[[tcp]]
+listen = "127.0.0.1:0"
+remote_addr = "127.0.0.1:22"
+[[tcp]]
+listen = "127.0.0.1:18423"
+remote_addr = "127.0.0.1:80"
+"#)).unwrap();
+        assert_eq!((plan.start.len(), plan.stop.len(), plan.unchanged), (1, 1, 1));
+        assert_eq!(plan.stop, vec!["127.0.0.1:18422".to_string()]);
+        listeners.apply(plan).await;
+
+        // another kind on the same address takes over the bound socket
+        let plan = listeners.prepare(specs(r#"
+[[tcp]]
+listen = "127.0.0.1:0"
+remote_addr = "127.0.0.1:22"
+[[http]]
+listen = "127.0.0.1:18423"
+"#)).unwrap();
+        assert_eq!((plan.start.len(), plan.stop.len(), plan.unchanged), (1, 1, 1));
+        let (Socket::Tcp(running), Socket::Tcp(starting)) = (&listeners.running["127.0.0.1:18423"].socket, &plan.start[0].socket) else {
+            unreachable!("tcp listeners");
+        };
+        assert!(Arc::ptr_eq(running, starting));
+
+        // an address in use fails the reload before anything is stopped
+        let _in_use = std::net::TcpListener::bind("127.0.0.1:18425").unwrap();
+        let in_use = listeners.prepare(specs(r#"
+[[tcp]]
+listen = "127.0.0.1:18425"
+remote_addr = "127.0.0.1:22"
+"#));
+        assert!(in_use.is_err());
+        assert_eq!(listeners.running.len(), 2);
+
+        let invalid = listeners.prepare(specs(r#"
+[[tcp]]
+listen = "127.0.0.1:18424"
+remote_addr = "no port"
+"#));
+        assert!(invalid.is_err());
+        let duplicate = listeners.prepare(specs(r#"
+[[tcp]]
+listen = "127.0.0.1:18424"
+remote_addr = "127.0.0.1:22"
+[[tcp]]
+listen = "127.0.0.1:18424"
+remote_addr = "127.0.0.1:80"
+"#));
+        assert!(duplicate.is_err());
+        let duplicate = listeners.prepare(specs(r#"
+[[tcp]]
+listen = "127.0.0.1:18424"
+remote_addr = "127.0.0.1:22"
+[[http]]
+listen = "127.0.0.1:18424"
+"#));
+        assert!(duplicate.is_err());
+
+        ctx.shutdown.cancel();
+        listeners.join().await;
+        assert!(listeners.is_empty());
+    }
+}
@@ -1,87 +1,104 @@
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::sync::Arc;
 
 use log::{error, info};
-use rand::distributions::WeightedError::AllWeightsZero;
-use tokio::net::TcpListener;
+use tokio::signal::unix::{signal, SignalKind};
 
-use crate::conf::{Config, HttpConfig, HttpsConfig, TcpConfig};
-use crate::connection_handle::{HttpsTunnel, HttpTunnel, serve, TcpTunnel};
-use crate::tcp_connector::{ATcpConnector, TcpConnector};
+use crate::acl::Acl;
+use crate::bandwidth::BandwidthLimits;
+use crate::conf::Config;
+use crate::connection_handle::TunnelContext;
+use crate::listeners::{Listeners, ListenerSpec};
+use crate::reload::ConfigWatcher;
+use crate::shutdown::{EXIT_LISTENER_FAILED, EXIT_OK, ShutdownSignals};
+use crate::tcp_connector::TcpConnector;
 
+mod acl;
+mod admin;
+mod auth;
+mod bandwidth;
 mod handshake_codec;
+mod http_codec;
+mod http_error;
+mod http_server;
 mod conf;
 mod tls_codec;
 mod dns;
 mod tcp_connector;
+mod upstream;
+mod proxy_protocol;
+mod client_stream;
 mod connection_handle;
+mod connection_table;
+mod limits;
+mod listeners;
+mod reload;
+mod socks4;
+mod socks5;
+mod metrics;
+mod shutdown;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     env_logger::init();
 
-    let conf = Config::from_cmd_line()?;
+    let config_path = Config::path_from_cmd_line()?;
+    let mut conf = Config::load(&config_path)?;
     info!("config: {:?}", conf);
     let tcp_connector = Arc::new(TcpConnector::new(conf.tunnel_config.target_connection.clone())?);
+    let acl = Arc::new(Acl::new(&conf.acl)?);
+    let bandwidth = Arc::new(BandwidthLimits::new(&conf.bandwidth));
+    let ctx = Arc::new(TunnelContext::new(tcp_connector, acl, bandwidth));
 
-    let mut join_handle_list = vec![];
+    let mut signals = ShutdownSignals::new()?;
+    let mut sighup = signal(SignalKind::hangup())?;
+    let mut watcher = ConfigWatcher::new(&config_path);
+    let mut listeners = Listeners::new(ctx.clone());
+    let plan = listeners.prepare(ListenerSpec::from_config(&conf))?;
+    listeners.apply(plan).await;
 
-    if let Some(ref http_conf) = conf.http {
-        let jh = tokio::spawn({
-            let http_conf = http_conf.clone();
-            let tcp_connector = tcp_connector.clone();
-            async move {
-                serve_http_tunnel(http_conf, tcp_connector).await?;
-                Ok::<(), anyhow::Error>(())
+    let mut exit_code = loop {
+        let watch_interval = conf.reload.watch_interval;
+        let should_reload = tokio::select! {
+            signal = signals.recv() => {
+                info!("received {}, stopping listeners", signal);
+                break EXIT_OK;
             }
-        });
-        join_handle_list.push(jh);
-    }
-    if let Some(ref https_conf) = conf.https {
-        let jh = tokio::spawn({
-            let https_conf = https_conf.clone();
-            let tcp_connector = tcp_connector.clone();
-            async move {
-                serve_https_tunnel(https_conf, tcp_connector).await?;
-                Ok::<(), anyhow::Error>(())
+            _ = sighup.recv() => {
+                info!("received SIGHUP, reloading {}", config_path.display());
+                watcher.changed();
+                true
             }
-        });
-        join_handle_list.push(jh);
-    }
-    for tcp_conf in &conf.tcp {
-        let jh = tokio::spawn({
-            let tcp_conf = tcp_conf.clone();
-            let tcp_connector = tcp_connector.clone();
-            async move {
-                serve_tcp_tunnel(tcp_conf, tcp_connector).await?;
-                Ok::<(), anyhow::Error>(())
+            _ = tokio::time::sleep(watch_interval.unwrap_or_default()), if watch_interval.is_some() => {
+                let changed = watcher.changed();
+                if changed {
+                    info!("{} changed, reloading", config_path.display());
+                }
+                changed
+            }
+            (key, result) = listeners.next_stopped() => {
+                if let Err(e) = result {
+                    error!("listener {} stopped: {:?}", key, e);
+                }
+                if listeners.is_empty() {
+                    error!("all listeners stopped");
+                    break EXIT_LISTENER_FAILED;
+                }
+                false
+            }
+        };
+        if should_reload {
+            if let Err(e) = reload::reload(&config_path, &mut conf, &ctx, &mut listeners).await {
+                error!("reload failed, keeping the running config: {:?}", e);
             }
-        });
-        join_handle_list.push(jh);
-    }
-
-    for jh in join_handle_list {
-        let join_result = jh.await;
-        if let Err(e) = join_result {
-            error!("join error: {}", e);
         }
-    }
+    };
+    ctx.shutdown.cancel();
+    listeners.join().await;
 
-    error!("proxy stopped");
-    Ok(())
-}
-
-pub async fn serve_http_tunnel(http_config: HttpConfig, tcp_connector: ATcpConnector) -> anyhow::Result<()> {
-    let http_tunnel = HttpTunnel::new(http_config, tcp_connector);
-    serve(Arc::new(http_tunnel)).await
-}
-
-pub async fn serve_https_tunnel(https_config: HttpsConfig, tcp_connector: ATcpConnector) -> anyhow::Result<()> {
-    let https_tunnel = HttpsTunnel::new(https_config, tcp_connector);
-    serve(Arc::new(https_tunnel)).await
+    let drained = shutdown::drain(&ctx.connections, conf.shutdown.drain_timeout, signals.recv()).await;
+    if exit_code == EXIT_OK {
+        exit_code = drained;
+    }
+    info!("proxy stopped, exit code: {}", exit_code);
+    std::process::exit(exit_code)
 }
-
-pub async fn serve_tcp_tunnel(tcp_config: TcpConfig, tcp_connector: ATcpConnector) -> anyhow::Result<()> {
-    let tcp_tunnel = TcpTunnel::new(tcp_config, tcp_connector);
-    serve(Arc::new(tcp_tunnel)).await
-}
\ No newline at end of file
@@ -0,0 +1,172 @@
+use std::sync::{Arc, LazyLock};
+use std::time::Instant;
+
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+
+use crate::conf::MetricsConfig;
+use crate::http_server;
+use crate::tcp_connector::ConnectError;
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// Buckets in seconds, from a cached DNS answer to a slow connect with retries.
+const LATENCY_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+pub struct Metrics {
+    registry: Registry,
+    pub accepted_connections: IntCounterVec,
+    pub active_connections: IntGaugeVec,
+    pub failed_connections: IntCounterVec,
+    /// client to target
+    pub bytes_up: IntCounterVec,
+    /// target to client
+    pub bytes_down: IntCounterVec,
+    pub dns_lookup_seconds: HistogramVec,
+    pub connect_seconds: HistogramVec,
+    pub handshake_errors: IntCounterVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("http_tunnel".to_string()), None).expect("valid prefix");
+        let counter = |name: &str, help: &str, labels: &[&str]| {
+            let counter = IntCounterVec::new(Opts::new(name, help), labels).expect("valid metric");
+            registry.register(Box::new(counter.clone())).expect("unique metric");
+            counter
+        };
+        let histogram = |name: &str, help: &str| {
+            let opts = HistogramOpts::new(name, help).buckets(LATENCY_BUCKETS.to_vec());
+            let histogram = HistogramVec::new(opts, &["result"]).expect("valid metric");
+            registry.register(Box::new(histogram.clone())).expect("unique metric");
+            histogram
+        };
+        let active_connections = IntGaugeVec::new(Opts::new("connections_active", "Connections being served"), &["tunnel"]).expect("valid metric");
+        registry.register(Box::new(active_connections.clone())).expect("unique metric");
+
+        Self {
+            accepted_connections: counter("connections_accepted_total", "Accepted client connections", &["tunnel"]),
+            failed_connections: counter("connections_failed_total", "Connections that were rejected or failed", &["tunnel", "reason"]),
+            bytes_up: counter("bytes_up_total", "Bytes relayed from clients to targets", &["tunnel"]),
+            bytes_down: counter("bytes_down_total", "Bytes relayed from targets to clients", &["tunnel"]),
+            dns_lookup_seconds: histogram("dns_lookup_duration_seconds", "Duration of DNS lookups"),
+            connect_seconds: histogram("connect_duration_seconds", "Duration of target connects, retries and upstream proxies included"),
+            handshake_errors: counter("handshake_errors_total", "Client handshakes that could not be parsed", &["protocol"]),
+            active_connections,
+            registry,
+        }
+    }
+
+    pub fn connection_failed(&self, tunnel: &str, reason: &str) {
+        self.failed_connections.with_label_values(&[tunnel, reason]).inc();
+    }
+
+    /// Counts a connection as active until the guard is dropped.
+    pub fn track_active(&self, tunnel: &str) -> ActiveGuard {
+        let gauge = self.active_connections.with_label_values(&[tunnel]);
+        gauge.inc();
+        ActiveGuard(gauge)
+    }
+
+    pub fn observe<T, E>(histogram: &HistogramVec, result: &Result<T, E>, start: Instant) {
+        let label = if result.is_ok() { "ok" } else { "error" };
+        histogram.with_label_values(&[label]).observe(start.elapsed().as_secs_f64());
+    }
+
+    fn encode(&self) -> anyhow::Result<Vec<u8>> {
+        let mut buf = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buf)?;
+        Ok(buf)
+    }
+}
+
+pub struct ActiveGuard(IntGauge);
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/// `reason` label of a connection that ended with `err`.
+pub fn failure_reason(err: &anyhow::Error) -> &'static str {
+    match err.downcast_ref::<ConnectError>() {
+        Some(ConnectError::Resolve(_)) => "resolve",
+        Some(ConnectError::Timeout(_)) => "connect_timeout",
+        Some(ConnectError::Io(err)) if err.kind() == std::io::ErrorKind::ConnectionRefused => "connect_refused",
+        Some(ConnectError::Io(_)) => "connect_error",
+        Some(ConnectError::Upstream(_)) => "upstream_proxy",
+        None => "error",
+    }
+}
+
+/// Serves `/metrics` in the Prometheus text format.
+pub struct MetricsServer {
+    metrics_config: MetricsConfig,
+}
+
+impl MetricsServer {
+    pub fn new(metrics_config: MetricsConfig) -> Self {
+        Self { metrics_config }
+    }
+
+    fn name(&self) -> &str {
+        self.metrics_config.listener.name.as_deref().unwrap_or("metrics")
+    }
+
+    /// Accepts scrapes until `shutdown` is cancelled, they are not counted as tunnel connections.
+    pub async fn serve(self: Arc<Self>, listener: Arc<TcpListener>, shutdown: CancellationToken) -> anyhow::Result<()> {
+        http_server::serve(self.name(), &self.metrics_config.listener, listener, shutdown, |mut stream| async move {
+            handle_request(&mut stream).await
+        }).await
+    }
+}
+
+async fn handle_request<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S) -> anyhow::Result<()> {
+    let request = http_server::read_request(stream).await?;
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/metrics") => {
+            let body = METRICS.encode()?;
+            http_server::write_response(stream, "200 OK", TextEncoder::new().format_type(), &body).await
+        }
+        _ => http_server::write_response(stream, "404 Not Found", "text/plain", b"not found\n").await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[test]
+    fn test_encode() -> anyhow::Result<()> {
+        METRICS.accepted_connections.with_label_values(&["test_encode"]).inc();
+        METRICS.connection_failed("test_encode", failure_reason(&ConnectError::Timeout(std::time::Duration::from_secs(1)).into()));
+        drop(METRICS.track_active("test_encode"));
+        let text = String::from_utf8(METRICS.encode()?)?;
+        assert!(text.contains("http_tunnel_connections_accepted_total{tunnel=\"test_encode\"} 1"));
+        assert!(text.contains("http_tunnel_connections_failed_total{reason=\"connect_timeout\",tunnel=\"test_encode\"} 1"));
+        assert!(text.contains("http_tunnel_connections_active{tunnel=\"test_encode\"} 0"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_scrape_not_counted() -> anyhow::Result<()> {
+        let config: MetricsConfig = toml::from_str("name = \"test_scrape\"\nlisten = \"127.0.0.1:0\"\n")?;
+        let listener = Arc::new(TcpListener::bind("127.0.0.1:0").await?);
+        let addr = listener.local_addr()?;
+        tokio::spawn(Arc::new(MetricsServer::new(config)).serve(listener, CancellationToken::new()));
+        for _ in 0..2 {
+            let mut stream = tokio::net::TcpStream::connect(addr).await?;
+            stream.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").await?;
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await?;
+            assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+            assert!(!response.contains("tunnel=\"test_scrape\""));
+        }
+        Ok(())
+    }
+}
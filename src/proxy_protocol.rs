@@ -0,0 +1,260 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::bail;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio_util::bytes::BytesMut;
+
+use crate::conf::ProxyProtocolVersion;
+
+pub const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// version 2, PROXY command
+const V2_VERSION_COMMAND: u8 = 0x21;
+const V2_FAMILY_TCP4: u8 = 0x11;
+const V2_FAMILY_TCP6: u8 = 0x21;
+const V2_COMMAND_LOCAL: u8 = 0x00;
+const V2_COMMAND_PROXY: u8 = 0x01;
+
+/// Longest v1 header, CRLF included.
+const V1_MAX_LEN: usize = 107;
+/// Generous bound for the v2 addresses and TLVs.
+const V2_MAX_LEN: usize = 4096;
+
+/// Host name sent by the client, the TLS SNI for https tunnels.
+pub const PP2_TYPE_AUTHORITY: u8 = 0x02;
+/// First of the custom TLV types (0xE0-0xEF), carries the tunnel name.
+pub const PP2_TYPE_TUNNEL_NAME: u8 = 0xE0;
+
+/// Both addresses in the same family, ipv4 ones are mapped when the other side is ipv6.
+fn same_family(src: SocketAddr, dst: SocketAddr) -> (SocketAddr, SocketAddr) {
+    let (src_ip, dst_ip) = (src.ip().to_canonical(), dst.ip().to_canonical());
+    let to_v6 = |ip: IpAddr| match ip {
+        IpAddr::V4(ip) => IpAddr::V6(ip.to_ipv6_mapped()),
+        ip => ip,
+    };
+    match (src_ip, dst_ip) {
+        (IpAddr::V4(_), IpAddr::V4(_)) | (IpAddr::V6(_), IpAddr::V6(_)) => (SocketAddr::new(src_ip, src.port()), SocketAddr::new(dst_ip, dst.port())),
+        _ => (SocketAddr::new(to_v6(src_ip), src.port()), SocketAddr::new(to_v6(dst_ip), dst.port())),
+    }
+}
+
+/// Human readable v1 header, `PROXY TCP4 <src> <dst> <sport> <dport>\r\n`.
+pub fn encode_v1(src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+    let (src, dst) = same_family(src, dst);
+    let family = if src.is_ipv4() { "TCP4" } else { "TCP6" };
+    format!("PROXY {} {} {} {} {}\r\n", family, src.ip(), dst.ip(), src.port(), dst.port()).into_bytes()
+}
+
+/// Binary v2 header followed by the `tlvs`.
+pub fn encode_v2(src: SocketAddr, dst: SocketAddr, tlvs: &[(u8, &[u8])]) -> Vec<u8> {
+    let (src, dst) = same_family(src, dst);
+    let mut addrs = vec![];
+    let family = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            addrs.extend_from_slice(&src_ip.octets());
+            addrs.extend_from_slice(&dst_ip.octets());
+            V2_FAMILY_TCP4
+        }
+        (IpAddr::V6(src_ip), IpAddr::V6(dst_ip)) => {
+            addrs.extend_from_slice(&src_ip.octets());
+            addrs.extend_from_slice(&dst_ip.octets());
+            V2_FAMILY_TCP6
+        }
+        _ => unreachable!("addresses are in the same family"),
+    };
+    addrs.extend_from_slice(&src.port().to_be_bytes());
+    addrs.extend_from_slice(&dst.port().to_be_bytes());
+    for (kind, value) in tlvs {
+        // a TLV value is at most u16::MAX bytes, longer ones are not worth sending
+        let Ok(len) = u16::try_from(value.len()) else {
+            continue;
+        };
+        // nor are the ones that would overflow the length of the whole header
+        if u16::try_from(addrs.len() + 3 + value.len()).is_err() {
+            continue;
+        }
+        addrs.push(*kind);
+        addrs.extend_from_slice(&len.to_be_bytes());
+        addrs.extend_from_slice(value);
+    }
+
+    let mut header = Vec::with_capacity(16 + addrs.len());
+    header.extend_from_slice(&V2_SIGNATURE);
+    header.push(V2_VERSION_COMMAND);
+    header.push(family);
+    let len = u16::try_from(addrs.len()).expect("bounded by the TLV loop");
+    header.extend_from_slice(&len.to_be_bytes());
+    header.extend_from_slice(&addrs);
+    header
+}
+
+/// Header announcing `client_addr`, connected to the tunnel on `local_addr`, to a backend.
+/// v2 carries `authority`, e.g. the SNI, and the tunnel name as TLVs.
+pub fn encode_header(version: ProxyProtocolVersion, client_addr: SocketAddr, local_addr: SocketAddr, authority: Option<&str>, tunnel_name: &str) -> Vec<u8> {
+    match version {
+        ProxyProtocolVersion::V1 => encode_v1(client_addr, local_addr),
+        ProxyProtocolVersion::V2 => {
+            let mut tlvs = vec![];
+            if let Some(authority) = authority {
+                tlvs.push((PP2_TYPE_AUTHORITY, authority.as_bytes()));
+            }
+            tlvs.push((PP2_TYPE_TUNNEL_NAME, tunnel_name.as_bytes()));
+            encode_v2(client_addr, local_addr, &tlvs)
+        }
+    }
+}
+
+/// Decoded inbound header.
+#[derive(Debug, PartialEq)]
+pub enum Header {
+    /// source address of the client
+    Proxied(SocketAddr),
+    /// `LOCAL` command or `UNKNOWN` family, e.g. health checks, the peer address stays
+    Local,
+}
+
+/// Reads the PROXY protocol header in front of the client's bytes into `buf` and takes it off,
+/// `Ok(None)` when the stream does not start with one. Bytes past the header stay in `buf`.
+pub async fn read_header<R: AsyncRead + Unpin>(r: &mut R, buf: &mut BytesMut) -> anyhow::Result<Option<Header>> {
+    loop {
+        let starts_like = |signature: &[u8]| buf[..buf.len().min(signature.len())] == signature[..buf.len().min(signature.len())];
+        if buf.starts_with(b"PROXY ") {
+            if let Some(end) = buf[..buf.len().min(V1_MAX_LEN)].windows(2).position(|it| it == b"\r\n") {
+                let line = buf.split_to(end + 2);
+                return parse_v1(std::str::from_utf8(&line[..end])?).map(Some);
+            }
+            if buf.len() >= V1_MAX_LEN {
+                bail!("PROXY protocol v1 header too long");
+            }
+        } else if buf.starts_with(&V2_SIGNATURE) {
+            if buf.len() >= 16 {
+                let len = u16::from_be_bytes([buf[14], buf[15]]) as usize;
+                if len > V2_MAX_LEN {
+                    bail!("PROXY protocol v2 header too long: {}", len);
+                }
+                if buf.len() >= 16 + len {
+                    let header = buf.split_to(16 + len);
+                    return parse_v2(header[12], header[13], &header[16..]).map(Some);
+                }
+            }
+        } else if !starts_like(b"PROXY ") && !starts_like(&V2_SIGNATURE) {
+            return Ok(None);
+        }
+        if r.read_buf(buf).await? == 0 {
+            bail!("connection closed before the PROXY protocol header was complete");
+        }
+    }
+}
+
+fn parse_v1(line: &str) -> anyhow::Result<Header> {
+    let fields = line.split(' ').collect::<Vec<_>>();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(Header::Local),
+        ["PROXY", family @ ("TCP4" | "TCP6"), src, _dst, src_port, _dst_port] => {
+            let ip = src.parse::<IpAddr>()?;
+            if ip.is_ipv4() != (*family == "TCP4") {
+                bail!("PROXY protocol v1 address does not match {}: {}", family, src);
+            }
+            Ok(Header::Proxied(SocketAddr::new(ip, src_port.parse()?)))
+        }
+        _ => bail!("invalid PROXY protocol v1 header: {:?}", line),
+    }
+}
+
+fn parse_v2(version_command: u8, family: u8, body: &[u8]) -> anyhow::Result<Header> {
+    if version_command >> 4 != 2 {
+        bail!("unsupported PROXY protocol version: {:#x}", version_command);
+    }
+    match version_command & 0x0F {
+        V2_COMMAND_LOCAL => return Ok(Header::Local),
+        V2_COMMAND_PROXY => {}
+        command => bail!("unsupported PROXY protocol v2 command: {:#x}", command),
+    }
+    // TLVs after the addresses are not used
+    match family >> 4 {
+        0x1 if body.len() >= 12 => {
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            Ok(Header::Proxied(SocketAddr::new(ip.into(), u16::from_be_bytes([body[8], body[9]]))))
+        }
+        0x2 if body.len() >= 36 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&body[..16])?);
+            Ok(Header::Proxied(SocketAddr::new(ip.into(), u16::from_be_bytes([body[32], body[33]]))))
+        }
+        0x1 | 0x2 => bail!("PROXY protocol v2 addresses truncated"),
+        // AF_UNSPEC or AF_UNIX, nothing usable as client address
+        _ => Ok(Header::Local),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+
+    async fn read_after(data: Vec<u8>) -> anyhow::Result<(Option<Header>, Vec<u8>)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            // split writes, the reader must wait for the rest of the signature
+            client.write_all(&data[..3]).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            client.write_all(&data[3..]).await.unwrap();
+        });
+        let (mut stream, _) = listener.accept().await?;
+        let mut buf = BytesMut::new();
+        let header = read_header(&mut stream, &mut buf).await?;
+        let mut rest = buf.to_vec();
+        stream.read_to_end(&mut rest).await?;
+        Ok((header, rest))
+    }
+
+    #[tokio::test]
+    async fn test_read_header() -> anyhow::Result<()> {
+        let v1 = b"PROXY TCP6 2001:db8::1 ::1 51234 8081\r\nGET / HTTP/1.1\r\n".to_vec();
+        assert_eq!(read_after(v1).await?, (Some(Header::Proxied("[2001:db8::1]:51234".parse()?)), b"GET / HTTP/1.1\r\n".to_vec()));
+
+        let mut v2 = encode_header(ProxyProtocolVersion::V2, "192.168.1.2:443".parse()?, "10.0.0.1:8443".parse()?, Some("a.com"), "web");
+        v2.extend_from_slice(b"\x16\x03\x01");
+        assert_eq!(read_after(v2).await?, (Some(Header::Proxied("192.168.1.2:443".parse()?)), b"\x16\x03\x01".to_vec()));
+
+        let post = b"POST / HTTP/1.1\r\n".to_vec();
+        assert_eq!(read_after(post.clone()).await?, (None, post));
+        assert_eq!(read_after(b"PROXY UNKNOWN\r\nx".to_vec()).await?, (Some(Header::Local), b"x".to_vec()));
+        assert!(read_after(b"PROXY TCP4 ::1 ::1 1 2\r\n".to_vec()).await.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_v1() {
+        let header = encode_v1("192.168.1.2:51234".parse().unwrap(), "10.0.0.1:8082".parse().unwrap());
+        assert_eq!(header, b"PROXY TCP4 192.168.1.2 10.0.0.1 51234 8082\r\n");
+        // accepted on a dual-stack listener
+        let header = encode_v1("[::ffff:192.168.1.2]:51234".parse().unwrap(), "[::ffff:10.0.0.1]:8082".parse().unwrap());
+        assert_eq!(header, b"PROXY TCP4 192.168.1.2 10.0.0.1 51234 8082\r\n");
+        let header = encode_v1("192.168.1.2:51234".parse().unwrap(), "[2001:db8::1]:8082".parse().unwrap());
+        assert_eq!(header, b"PROXY TCP6 ::ffff:192.168.1.2 2001:db8::1 51234 8082\r\n");
+    }
+
+    #[test]
+    fn test_encode_v2() {
+        let header = encode_header(ProxyProtocolVersion::V2, "192.168.1.2:443".parse().unwrap(), "10.0.0.1:8443".parse().unwrap(), Some("a.com"), "web");
+        let mut expected = V2_SIGNATURE.to_vec();
+        expected.extend_from_slice(&[0x21, 0x11, 0x00, 12 + 8 + 6]);
+        expected.extend_from_slice(&[192, 168, 1, 2, 10, 0, 0, 1, 0x01, 0xbb, 0x20, 0xfb]);
+        expected.extend_from_slice(&[PP2_TYPE_AUTHORITY, 0x00, 0x05]);
+        expected.extend_from_slice(b"a.com");
+        expected.extend_from_slice(&[PP2_TYPE_TUNNEL_NAME, 0x00, 0x03]);
+        expected.extend_from_slice(b"web");
+        assert_eq!(header, expected);
+
+        // together the values exceed the u16 length, the one that does not fit is dropped
+        let big = vec![b'x'; 40000];
+        let header = encode_v2("192.168.1.2:443".parse().unwrap(), "10.0.0.1:8443".parse().unwrap(), &[(PP2_TYPE_AUTHORITY, &big), (PP2_TYPE_TUNNEL_NAME, &big)]);
+        assert_eq!(u16::from_be_bytes([header[14], header[15]]) as usize, 12 + 3 + 40000);
+        assert_eq!(header.len(), 16 + 12 + 3 + 40000);
+    }
+}
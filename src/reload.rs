@@ -0,0 +1,58 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use log::info;
+
+use crate::acl::Acl;
+use crate::bandwidth::BandwidthLimits;
+use crate::conf::Config;
+use crate::connection_handle::ATunnelContext;
+use crate::listeners::{Listeners, ListenerSpec};
+use crate::tcp_connector::TcpConnector;
+
+/// Applies the config at `path`, the running one is kept when anything in it is invalid.
+/// Connections started before keep their settings.
+pub async fn reload(path: &Path, conf: &mut Config, ctx: &ATunnelContext, listeners: &mut Listeners) -> anyhow::Result<()> {
+    let new_conf = Config::load(path)?;
+    let acl = Arc::new(Acl::new(&new_conf.acl)?);
+    // rebuilding the connector drops its dns cache, so only when its settings changed
+    let tcp_connector = match new_conf.tunnel_config.target_connection == conf.tunnel_config.target_connection {
+        true => None,
+        false => Some(Arc::new(TcpConnector::new(new_conf.tunnel_config.target_connection.clone())?)),
+    };
+    // rebuilding the limits refills their buckets
+    let bandwidth = match new_conf.bandwidth == conf.bandwidth {
+        true => None,
+        false => Some(Arc::new(BandwidthLimits::new(&new_conf.bandwidth))),
+    };
+    let plan = listeners.prepare(ListenerSpec::from_config(&new_conf))?;
+
+    ctx.swap(tcp_connector, acl, bandwidth);
+    listeners.apply(plan).await;
+    info!("config reloaded from {}", path.display());
+    *conf = new_conf;
+    Ok(())
+}
+
+/// Notices changes of the config file by its modification time.
+pub struct ConfigWatcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+}
+
+impl ConfigWatcher {
+    pub fn new(path: &Path) -> Self {
+        let mut watcher = Self { path: path.to_path_buf(), modified: None };
+        watcher.changed();
+        watcher
+    }
+
+    /// Whether the file changed since the last call, a missing file counts as a change.
+    pub fn changed(&mut self) -> bool {
+        let modified = std::fs::metadata(&self.path).and_then(|it| it.modified()).ok();
+        let changed = modified != self.modified;
+        self.modified = modified;
+        changed
+    }
+}
@@ -0,0 +1,124 @@
+use std::future::Future;
+use std::time::Duration;
+
+use log::{info, warn};
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::time::Instant;
+
+use crate::connection_table::ConnectionTable;
+
+/// Exit status after draining every connection.
+pub const EXIT_OK: i32 = 0;
+/// Exit status when listeners stopped on their own, e.g. a bind error.
+pub const EXIT_LISTENER_FAILED: i32 = 1;
+/// Exit status when connections were still open at the end of the drain timeout.
+pub const EXIT_FORCE_CLOSED: i32 = 2;
+
+const DRAIN_LOG_INTERVAL: Duration = Duration::from_secs(1);
+/// Aborted connection tasks are gone after their next poll, this only bounds a stuck runtime.
+const FORCE_CLOSE_WAIT: Duration = Duration::from_secs(1);
+
+pub struct ShutdownSignals {
+    sigterm: Signal,
+    sigint: Signal,
+}
+
+impl ShutdownSignals {
+    pub fn new() -> anyhow::Result<Self> {
+        Ok(Self { sigterm: signal(SignalKind::terminate())?, sigint: signal(SignalKind::interrupt())? })
+    }
+
+    pub async fn recv(&mut self) -> &'static str {
+        tokio::select! {
+            _ = self.sigterm.recv() => "SIGTERM",
+            _ = self.sigint.recv() => "SIGINT",
+        }
+    }
+}
+
+/// Waits until every connection ended, closes the rest after `timeout` or once `signal` arrives.
+/// Returns `EXIT_OK` when all connections ended on their own, `EXIT_FORCE_CLOSED` otherwise.
+pub async fn drain(connections: &ConnectionTable, timeout: Duration, signal: impl Future<Output = &'static str>) -> i32 {
+    let deadline = Instant::now() + timeout;
+    tokio::pin!(signal);
+    loop {
+        let remaining = connections.len();
+        if remaining == 0 {
+            info!("all connections drained");
+            return EXIT_OK;
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            warn!("drain timeout of {:?} reached, closing {} connections", timeout, remaining);
+            break;
+        }
+        info!("draining {} connections, closing them in {:.0}s", remaining, (deadline - now).as_secs_f64());
+        tokio::select! {
+            _ = connections.wait_empty() => {}
+            _ = tokio::time::sleep(DRAIN_LOG_INTERVAL.min(deadline - now)) => {}
+            signal = &mut signal => {
+                warn!("received {} while draining, closing {} connections", signal, remaining);
+                break;
+            }
+        }
+    }
+
+    connections.close_all();
+    if tokio::time::timeout(FORCE_CLOSE_WAIT, connections.wait_empty()).await.is_err() {
+        warn!("{} connections did not close", connections.len());
+    }
+    EXIT_FORCE_CLOSED
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::sync::oneshot;
+    use tokio::task::JoinHandle;
+
+    use super::*;
+    use crate::connection_table::ConnectionGuard;
+
+    /// Registers a connection whose task runs `serve`.
+    fn spawn_conn(table: &Arc<ConnectionTable>, serve: impl Future<Output = ()> + Send + 'static) -> JoinHandle<()> {
+        let (registered_tx, registered_rx) = oneshot::channel::<ConnectionGuard>();
+        let task = tokio::spawn(async move {
+            let _guard = registered_rx.await;
+            serve.await
+        });
+        assert!(registered_tx.send(table.register("test_drain", "127.0.0.1:50000".parse().unwrap(), task.abort_handle())).is_ok());
+        task
+    }
+
+    #[tokio::test]
+    async fn test_drain() {
+        let table = Arc::new(ConnectionTable::new());
+        spawn_conn(&table, tokio::time::sleep(Duration::from_millis(50)));
+        let start = Instant::now();
+        assert_eq!(drain(&table, Duration::from_secs(5), std::future::pending()).await, EXIT_OK);
+        // woken by the connection ending, not by the log interval
+        assert!(start.elapsed() < DRAIN_LOG_INTERVAL, "{:?}", start.elapsed());
+
+        let task = spawn_conn(&table, std::future::pending());
+        assert_eq!(drain(&table, Duration::from_millis(100), std::future::pending()).await, EXIT_FORCE_CLOSED);
+        assert!(table.is_empty());
+        assert!(task.await.unwrap_err().is_cancelled());
+
+        // a second signal does not wait for the timeout
+        spawn_conn(&table, std::future::pending());
+        let start = Instant::now();
+        assert_eq!(drain(&table, Duration::from_secs(5), async { "SIGINT" }).await, EXIT_FORCE_CLOSED);
+        assert!(start.elapsed() < DRAIN_LOG_INTERVAL, "{:?}", start.elapsed());
+        assert!(table.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_signals() -> anyhow::Result<()> {
+        let mut signals = ShutdownSignals::new()?;
+        let status = std::process::Command::new("kill").args(["-TERM", &std::process::id().to_string()]).status()?;
+        assert!(status.success());
+        assert_eq!(tokio::time::timeout(Duration::from_secs(5), signals.recv()).await?, "SIGTERM");
+        Ok(())
+    }
+}
@@ -0,0 +1,85 @@
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use anyhow::bail;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub const SOCKS4_VERSION: u8 = 0x04;
+pub const CMD_CONNECT: u8 = 0x01;
+
+pub const REPLY_GRANTED: u8 = 0x5A;
+pub const REPLY_REJECTED: u8 = 0x5B;
+
+const MAX_FIELD_LEN: usize = 255;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Request {
+    pub command: u8,
+    pub port: u16,
+    pub user_id: String,
+    /// ip literal for SOCKS4, hostname when the SOCKS4a `0.0.0.x` form is used
+    pub host: String,
+}
+
+/// Reads a null terminated field, as used for USERID and the SOCKS4a hostname.
+async fn read_cstr<R: AsyncRead + Unpin>(r: &mut R) -> anyhow::Result<String> {
+    let mut buf = vec![];
+    loop {
+        let b = r.read_u8().await?;
+        if b == 0 {
+            break;
+        }
+        if buf.len() >= MAX_FIELD_LEN {
+            bail!("field too long");
+        }
+        buf.push(b);
+    }
+    String::from_utf8(buf).map_err(|_| anyhow::anyhow!("field not utf8"))
+}
+
+pub async fn read_request<R: AsyncRead + Unpin>(r: &mut R) -> anyhow::Result<Request> {
+    let version = r.read_u8().await?;
+    if version != SOCKS4_VERSION {
+        bail!("unsupported socks version: {}", version);
+    }
+    let command = r.read_u8().await?;
+    let port = r.read_u16().await?;
+    let mut ip = [0u8; 4];
+    r.read_exact(&mut ip).await?;
+    let user_id = read_cstr(r).await?;
+
+    // SOCKS4a: 0.0.0.x with x != 0 means the hostname follows the user id
+    let host = if ip[..3] == [0, 0, 0] && ip[3] != 0 {
+        read_cstr(r).await?
+    } else {
+        Ipv4Addr::from(ip).to_string()
+    };
+    Ok(Request { command, port, user_id, host })
+}
+
+pub async fn write_reply<W: AsyncWrite + Unpin>(w: &mut W, status: u8, addr: SocketAddrV4) -> anyhow::Result<()> {
+    let mut buf = vec![0x00, status];
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+    buf.extend_from_slice(&addr.ip().octets());
+    w.write_all(&buf).await?;
+    w.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_request() -> anyhow::Result<()> {
+        let mut data: &[u8] = &[0x04, 0x01, 0x00, 0x50, 192, 168, 1, 2, b'b', b'o', b'b', 0x00];
+        let request = read_request(&mut data).await?;
+        assert_eq!(request, Request { command: CMD_CONNECT, port: 80, user_id: "bob".to_string(), host: "192.168.1.2".to_string() });
+
+        let mut data: &[u8] = b"\x04\x01\x01\xbb\x00\x00\x00\x01\x00example.com\x00";
+        let request = read_request(&mut data).await?;
+        assert_eq!(request.port, 443);
+        assert_eq!(request.user_id, "");
+        assert_eq!(request.host, "example.com");
+        Ok(())
+    }
+}
@@ -0,0 +1,202 @@
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::bail;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::tcp_connector::ConnectError;
+
+pub const SOCKS5_VERSION: u8 = 0x05;
+pub const USER_PASS_VERSION: u8 = 0x01;
+
+pub const METHOD_NO_AUTH: u8 = 0x00;
+pub const METHOD_USER_PASS: u8 = 0x02;
+pub const METHOD_NO_ACCEPTABLE: u8 = 0xFF;
+
+pub const CMD_CONNECT: u8 = 0x01;
+
+pub const ATYP_IPV4: u8 = 0x01;
+pub const ATYP_DOMAIN: u8 = 0x03;
+pub const ATYP_IPV6: u8 = 0x04;
+
+/// Reply codes, RFC 1928 section 6.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reply {
+    Succeeded = 0x00,
+    GeneralFailure = 0x01,
+    NotAllowed = 0x02,
+    NetworkUnreachable = 0x03,
+    HostUnreachable = 0x04,
+    ConnectionRefused = 0x05,
+    TtlExpired = 0x06,
+    CommandNotSupported = 0x07,
+    AddressTypeNotSupported = 0x08,
+}
+
+impl Reply {
+    pub fn from_connect_error(err: &anyhow::Error) -> Self {
+        match err.downcast_ref::<ConnectError>() {
+            Some(ConnectError::Resolve(_)) => Reply::HostUnreachable,
+            Some(ConnectError::Timeout(_)) => Reply::TtlExpired,
+            Some(ConnectError::Io(err)) => match err.kind() {
+                std::io::ErrorKind::ConnectionRefused => Reply::ConnectionRefused,
+                std::io::ErrorKind::NetworkUnreachable => Reply::NetworkUnreachable,
+                std::io::ErrorKind::HostUnreachable => Reply::HostUnreachable,
+                std::io::ErrorKind::TimedOut => Reply::TtlExpired,
+                _ => Reply::GeneralFailure,
+            },
+            Some(ConnectError::Upstream(_)) | None => Reply::GeneralFailure,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TargetAddr {
+    Ip(SocketAddr),
+    Domain(String, u16),
+}
+
+impl TargetAddr {
+    pub fn host(&self) -> String {
+        match self {
+            TargetAddr::Ip(addr) => addr.ip().to_string(),
+            TargetAddr::Domain(domain, _) => domain.clone(),
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        match self {
+            TargetAddr::Ip(addr) => addr.port(),
+            TargetAddr::Domain(_, port) => *port,
+        }
+    }
+
+    /// Encodes ATYP, DST.ADDR and DST.PORT.
+    pub fn write_to(&self, buf: &mut Vec<u8>) -> anyhow::Result<()> {
+        match self {
+            TargetAddr::Ip(SocketAddr::V4(addr)) => {
+                buf.push(ATYP_IPV4);
+                buf.extend_from_slice(&addr.ip().octets());
+            }
+            TargetAddr::Ip(SocketAddr::V6(addr)) => {
+                buf.push(ATYP_IPV6);
+                buf.extend_from_slice(&addr.ip().octets());
+            }
+            TargetAddr::Domain(domain, _) => {
+                if domain.is_empty() || domain.len() > u8::MAX as usize {
+                    bail!("invalid domain length: {}", domain.len());
+                }
+                buf.push(ATYP_DOMAIN);
+                buf.push(domain.len() as u8);
+                buf.extend_from_slice(domain.as_bytes());
+            }
+        }
+        buf.extend_from_slice(&self.port().to_be_bytes());
+        Ok(())
+    }
+
+    /// Reads ATYP, DST.ADDR and DST.PORT, `Ok(None)` means an unknown address type.
+    pub async fn read_from<R: AsyncRead + Unpin>(r: &mut R) -> anyhow::Result<Option<Self>> {
+        let atyp = r.read_u8().await?;
+        let addr = match atyp {
+            ATYP_IPV4 => {
+                let mut ip = [0u8; 4];
+                r.read_exact(&mut ip).await?;
+                TargetAddr::Ip(SocketAddr::new(Ipv4Addr::from(ip).into(), r.read_u16().await?))
+            }
+            ATYP_IPV6 => {
+                let mut ip = [0u8; 16];
+                r.read_exact(&mut ip).await?;
+                TargetAddr::Ip(SocketAddr::new(Ipv6Addr::from(ip).into(), r.read_u16().await?))
+            }
+            ATYP_DOMAIN => {
+                let len = r.read_u8().await? as usize;
+                let mut domain = vec![0u8; len];
+                r.read_exact(&mut domain).await?;
+                let domain = String::from_utf8(domain).map_err(|_| anyhow::anyhow!("domain not utf8"))?;
+                TargetAddr::Domain(domain, r.read_u16().await?)
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(addr))
+    }
+}
+
+/// Reads the method selection message and returns the methods offered by the client.
+pub async fn read_methods<R: AsyncRead + Unpin>(r: &mut R) -> anyhow::Result<Vec<u8>> {
+    let version = r.read_u8().await?;
+    if version != SOCKS5_VERSION {
+        bail!("unsupported socks version: {}", version);
+    }
+    let n_methods = r.read_u8().await? as usize;
+    let mut methods = vec![0u8; n_methods];
+    r.read_exact(&mut methods).await?;
+    Ok(methods)
+}
+
+/// Reads the username/password sub-negotiation request, RFC 1929.
+pub async fn read_user_pass<R: AsyncRead + Unpin>(r: &mut R) -> anyhow::Result<(String, String)> {
+    let version = r.read_u8().await?;
+    if version != USER_PASS_VERSION {
+        bail!("unsupported username/password auth version: {}", version);
+    }
+    let len = r.read_u8().await? as usize;
+    let mut username = vec![0u8; len];
+    r.read_exact(&mut username).await?;
+    let len = r.read_u8().await? as usize;
+    let mut password = vec![0u8; len];
+    r.read_exact(&mut password).await?;
+    Ok((String::from_utf8_lossy(&username).into_owned(), String::from_utf8_lossy(&password).into_owned()))
+}
+
+/// Reads the request header, the command and target address are validated by the caller.
+pub async fn read_request<R: AsyncRead + Unpin>(r: &mut R) -> anyhow::Result<(u8, Option<TargetAddr>)> {
+    let mut header = [0u8; 3];
+    r.read_exact(&mut header).await?;
+    if header[0] != SOCKS5_VERSION {
+        bail!("unsupported socks version: {}", header[0]);
+    }
+    let addr = TargetAddr::read_from(r).await?;
+    Ok((header[1], addr))
+}
+
+pub async fn write_reply<W: AsyncWrite + Unpin>(w: &mut W, reply: Reply, bind_addr: SocketAddr) -> anyhow::Result<()> {
+    let mut buf = vec![SOCKS5_VERSION, reply as u8, 0x00];
+    TargetAddr::Ip(bind_addr).write_to(&mut buf)?;
+    w.write_all(&buf).await?;
+    w.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_request() -> anyhow::Result<()> {
+        let mut data: &[u8] = &[0x05, 0x01, 0x00, 0x03, 11, b'e', b'x', b'a', b'm', b'p', b'l', b'e', b'.', b'c', b'o', b'm', 0x01, 0xbb];
+        let (cmd, addr) = read_request(&mut data).await?;
+        assert_eq!(cmd, CMD_CONNECT);
+        assert_eq!(addr, Some(TargetAddr::Domain("example.com".to_string(), 443)));
+
+        let mut data: &[u8] = &[0x05, 0x01, 0x00, 0x04, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0x00, 0x50];
+        let (_, addr) = read_request(&mut data).await?;
+        assert_eq!(addr, Some(TargetAddr::Ip("[::1]:80".parse()?)));
+        assert_eq!(addr.unwrap().host(), "::1");
+
+        let mut data: &[u8] = &[0x05, 0x01, 0x00, 0x09];
+        let (_, addr) = read_request(&mut data).await?;
+        assert_eq!(addr, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_reply() -> anyhow::Result<()> {
+        let mut buf = vec![];
+        let bind_addr = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 1080);
+        write_reply(&mut buf, Reply::ConnectionRefused, bind_addr).await?;
+        assert_eq!(buf, vec![0x05, 0x05, 0x00, 0x01, 10, 0, 0, 1, 0x04, 0x38]);
+        Ok(())
+    }
+}
@@ -1,88 +1,329 @@
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
 use anyhow::bail;
-use log::{error, info};
+use log::{debug, error, info};
 use rand::prelude::SliceRandom;
 use rand::thread_rng;
+use tokio::net::TcpStream;
+use tokio::task::JoinSet;
 
-use crate::conf::TargetConnectionConfig;
+use crate::conf::{TargetConnectionConfig, UpstreamProtocol, UpstreamProxyConfig};
 use crate::dns;
 use crate::dns::TDNSResolver;
+use crate::metrics::{Metrics, METRICS};
+use crate::socks5::TargetAddr;
+use crate::upstream;
 
 pub type ATcpConnector = Arc<TcpConnector>;
 
+/// Failure of [`TcpConnector::connect`], handlers downcast to it to pick a protocol specific error reply.
+#[derive(Debug)]
+pub enum ConnectError {
+    Resolve(anyhow::Error),
+    Timeout(Duration),
+    Io(std::io::Error),
+    /// a parent proxy refused to open the tunnel
+    Upstream(String),
+}
+
+impl Display for ConnectError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectError::Resolve(err) => write!(f, "resolve failed: {}", err),
+            ConnectError::Timeout(timeout) => write!(f, "connect timeout, reach limit: {:?}", timeout),
+            ConnectError::Io(err) => write!(f, "connect failed: {}", err),
+            ConnectError::Upstream(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ConnectError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConnectError::Resolve(err) => Some(err.as_ref()),
+            ConnectError::Timeout(_) | ConnectError::Upstream(_) => None,
+            ConnectError::Io(err) => Some(err),
+        }
+    }
+}
+
+/// Delay between two connection attempts, RFC 8305 section 5.
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+/// How long a failed address is tried after the others.
+const FAILED_ADDR_TTL: Duration = Duration::from_secs(30);
+
 pub struct TcpConnector {
     target_connection_config: TargetConnectionConfig,
     dns_resolver: TDNSResolver,
+    failed_addrs: Mutex<HashMap<SocketAddr, Instant>>,
 }
 
 impl TcpConnector {
     pub fn new(target_connection_config: TargetConnectionConfig) -> anyhow::Result<Self> {
-        let dns_resolver = dns::DnsResolver::new(target_connection_config.dns_cache_ttl)?;
+        let dns_resolver = dns::DnsResolver::new(target_connection_config.dns_cache_ttl, target_connection_config.address_family)?;
         let dns_resolver = Arc::new(dns_resolver);
-        Ok(Self { target_connection_config, dns_resolver })
+        Ok(Self { target_connection_config, dns_resolver, failed_addrs: Mutex::new(HashMap::new()) })
+    }
+
+    pub fn dns_resolver(&self) -> &TDNSResolver {
+        &self.dns_resolver
     }
+
     async fn to_socket_addr(&self, host: &str, port: u16) -> anyhow::Result<Vec<SocketAddr>> {
-        if let Ok(addr) = host.parse::<Ipv4Addr>() {
-            let addr = SocketAddrV4::new(addr, port);
-            return Ok(vec![SocketAddr::V4(addr)]);
+        if let Ok(addr) = host.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(addr, port)]);
         }
         let addrs = self.dns_resolver.resolve(host).await?;
-        Ok(addrs.iter().map(|it| SocketAddr::new(IpAddr::V4(*it), port)).collect())
+        Ok(addrs.iter().map(|it| SocketAddr::new(*it, port)).collect())
     }
 
+    /// Connects to `host:port`, through the configured chain of parent proxies if any.
     pub async fn connect(&self, host: &str, port: u16) -> anyhow::Result<tokio::net::TcpStream> {
-        let mut sock_addrs = match self.to_socket_addr(host, port).await {
+        let start = Instant::now();
+        let result = self.connect_with_deadline(host, port).await;
+        Metrics::observe(&METRICS.connect_seconds, &result, start);
+        result
+    }
+
+    async fn connect_with_deadline(&self, host: &str, port: u16) -> anyhow::Result<tokio::net::TcpStream> {
+        let Some(deadline) = self.target_connection_config.connect_deadline else {
+            return self.connect_chain(host, port).await;
+        };
+        match tokio::time::timeout(deadline, self.connect_chain(host, port)).await {
+            Ok(connect_result) => connect_result,
+            Err(_) => {
+                error!("connect deadline exceeded {}:{}, reach limit: {:?}", host, port, deadline);
+                Err(ConnectError::Timeout(deadline).into())
+            }
+        }
+    }
+
+    async fn connect_chain(&self, host: &str, port: u16) -> anyhow::Result<tokio::net::TcpStream> {
+        let upstream = &self.target_connection_config.upstream;
+        let Some(first) = upstream.first() else {
+            return self.connect_direct(host, port).await;
+        };
+
+        let (first_host, first_port) = split_host_port(&first.addr)?;
+        let mut tcp_stream = self.connect_direct(&first_host, first_port).await?;
+        for (idx, proxy) in upstream.iter().enumerate() {
+            let (next_host, next_port) = match upstream.get(idx + 1) {
+                Some(next) => split_host_port(&next.addr)?,
+                None => (host.to_string(), port),
+            };
+            let connect_result = tokio::time::timeout(
+                self.target_connection_config.connect_timeout,
+                self.upstream_connect(&mut tcp_stream, proxy, &next_host, next_port),
+            ).await;
+            match connect_result {
+                Ok(Ok(())) => {
+                    info!("upstream proxy {} tunneled to {}:{}", proxy.addr, next_host, next_port);
+                }
+                Ok(Err(err)) => {
+                    error!("upstream proxy {} failed to connect {}:{}, err: {:?}", proxy.addr, next_host, next_port, err);
+                    return Err(err);
+                }
+                Err(_) => {
+                    error!("upstream proxy {} connect timeout {}:{}", proxy.addr, next_host, next_port);
+                    return Err(ConnectError::Timeout(self.target_connection_config.connect_timeout).into());
+                }
+            }
+        }
+        Ok(tcp_stream)
+    }
+
+    async fn upstream_connect(&self, tcp_stream: &mut TcpStream, proxy: &UpstreamProxyConfig, host: &str, port: u16) -> anyhow::Result<()> {
+        match proxy.protocol {
+            UpstreamProtocol::Http => upstream::http_connect(tcp_stream, proxy, host, port).await,
+            UpstreamProtocol::Socks5 => {
+                let target = match host.parse::<IpAddr>() {
+                    Ok(ip) => TargetAddr::Ip(SocketAddr::new(ip, port)),
+                    Err(_) if proxy.remote_dns => TargetAddr::Domain(host.to_string(), port),
+                    Err(_) => {
+                        let sock_addrs = self.to_socket_addr(host, port).await.map_err(ConnectError::Resolve)?;
+                        let sock_addr = sock_addrs.first()
+                            .ok_or_else(|| ConnectError::Resolve(anyhow::anyhow!("No address found for host: {}", host)))?;
+                        TargetAddr::Ip(*sock_addr)
+                    }
+                };
+                upstream::socks5_connect(tcp_stream, proxy, &target).await
+            }
+        }
+    }
+
+    async fn connect_direct(&self, host: &str, port: u16) -> anyhow::Result<tokio::net::TcpStream> {
+        let sock_addrs = match self.to_socket_addr(host, port).await {
             Ok(addrs) => {
                 addrs
             }
             Err(err) => {
-                bail!("failed to resolve host: {}, err: {:?}", host, err);
+                error!("failed to resolve host: {}, err: {:?}", host, err);
+                return Err(ConnectError::Resolve(err).into());
             }
         };
 
         info!("resolve done, host: {}, port: {}, sock_addrs: {:?}", host, port, sock_addrs);
+        if sock_addrs.is_empty() {
+            return Err(ConnectError::Resolve(anyhow::anyhow!("No address found for host: {}", host)).into());
+        }
 
-        let sock_addr = sock_addrs.choose(&mut thread_rng()).ok_or(anyhow::anyhow!("No address found for host: {}", host))?;
-        let connect_result = tokio::time::timeout(
-            self.target_connection_config.connect_timeout,
-            tokio::net::TcpStream::connect(sock_addr),
-        ).await;
-        let tcp_stream = match connect_result {
-            Ok(Ok(tcp_stream)) => tcp_stream,
-
-            Ok(Err(err)) => {
-                error!("failed to connect to {}:{}, err: {:?}", host, port, err);
-                return Err(err.into());
+        let retry = &self.target_connection_config.retry;
+        let max_attempts = retry.max_attempts.max(1);
+        // without failover every attempt goes to the address that was preferred at first
+        let pinned_addr = if retry.failover {
+            None
+        } else {
+            self.sort_addrs(sock_addrs.clone()).first().copied()
+        };
+        let mut attempt = 1;
+        loop {
+            let attempt_addrs = match pinned_addr {
+                Some(sock_addr) => vec![sock_addr],
+                None => self.sort_addrs(sock_addrs.clone()),
+            };
+            match self.race(host, attempt_addrs).await {
+                Ok(tcp_stream) => {
+                    info!("connect attempt {}/{} to {}:{} succeeded", attempt, max_attempts, host, port);
+                    let _ = tcp_stream.set_nodelay(true);
+                    return Ok(tcp_stream);
+                }
+                Err(err) if attempt >= max_attempts => {
+                    error!("connect attempt {}/{} to {}:{} failed, err: {}, giving up", attempt, max_attempts, host, port, err);
+                    return Err(err.into());
+                }
+                Err(err) => {
+                    let backoff = retry.backoff.saturating_mul(1 << (attempt - 1).min(16));
+                    error!("connect attempt {}/{} to {}:{} failed, err: {}, retry in {:?}", attempt, max_attempts, host, port, err, backoff);
+                    tokio::time::sleep(backoff).await;
+                    attempt += 1;
+                }
             }
-            Err(elapsed) => {
-                error!("connect timeout {}:{}, reach limit: {:?}", host, port, self.target_connection_config.connect_timeout);
-                return Err(elapsed.into());
+        }
+    }
+
+    fn sort_addrs(&self, sock_addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        let failed_addrs = self.failed_addrs.lock().unwrap();
+        sort_addrs(sock_addrs, |addr| failed_addrs.get(addr).is_some_and(|it| it.elapsed() < FAILED_ADDR_TTL))
+    }
+
+    /// Happy Eyeballs (RFC 8305): a new attempt is started every [`CONNECTION_ATTEMPT_DELAY`] or as soon
+    /// as one fails, the first established connection wins and the pending attempts are cancelled.
+    async fn race(&self, host: &str, sock_addrs: Vec<SocketAddr>) -> Result<TcpStream, ConnectError> {
+        let connect_timeout = self.target_connection_config.connect_timeout;
+        let mut sock_addrs = sock_addrs.into_iter().peekable();
+        // dropping the JoinSet aborts the attempts still in flight
+        let mut attempts = JoinSet::new();
+        let mut last_err = None;
+        loop {
+            if attempts.is_empty() {
+                let Some(sock_addr) = sock_addrs.next() else {
+                    return Err(last_err.unwrap_or(ConnectError::Timeout(connect_timeout)));
+                };
+                attempts.spawn(connect_addr(sock_addr, connect_timeout));
             }
-        };
+            tokio::select! {
+                Some(joined) = attempts.join_next() => {
+                    let (sock_addr, connect_result) = joined.map_err(|err| ConnectError::Io(err.into()))?;
+                    match connect_result {
+                        Ok(tcp_stream) => {
+                            debug!("connected to {} ({})", host, sock_addr);
+                            self.failed_addrs.lock().unwrap().remove(&sock_addr);
+                            return Ok(tcp_stream);
+                        }
+                        Err(err) => {
+                            error!("failed to connect to {} ({}), err: {}", host, sock_addr, err);
+                            self.record_failure(sock_addr);
+                            last_err = Some(err);
+                            if let Some(sock_addr) = sock_addrs.next() {
+                                attempts.spawn(connect_addr(sock_addr, connect_timeout));
+                            }
+                        }
+                    }
+                }
+                _ = tokio::time::sleep(CONNECTION_ATTEMPT_DELAY), if sock_addrs.peek().is_some() => {
+                    let sock_addr = sock_addrs.next().unwrap();
+                    debug!("no connection to {} after {:?}, racing {}", host, CONNECTION_ATTEMPT_DELAY, sock_addr);
+                    attempts.spawn(connect_addr(sock_addr, connect_timeout));
+                }
+            }
+        }
+    }
 
-        let _ = tcp_stream.set_nodelay(true);
-        Ok(tcp_stream)
+    fn record_failure(&self, sock_addr: SocketAddr) {
+        let mut failed_addrs = self.failed_addrs.lock().unwrap();
+        failed_addrs.retain(|_, failed_at| failed_at.elapsed() < FAILED_ADDR_TTL);
+        failed_addrs.insert(sock_addr, Instant::now());
+    }
+}
+
+async fn connect_addr(sock_addr: SocketAddr, connect_timeout: Duration) -> (SocketAddr, Result<TcpStream, ConnectError>) {
+    let connect_result = match tokio::time::timeout(connect_timeout, TcpStream::connect(sock_addr)).await {
+        Ok(Ok(tcp_stream)) => Ok(tcp_stream),
+        Ok(Err(err)) => Err(ConnectError::Io(err)),
+        Err(_) => Err(ConnectError::Timeout(connect_timeout)),
+    };
+    (sock_addr, connect_result)
+}
+
+/// Orders addresses for racing (RFC 8305 section 4): the families are interleaved starting with
+/// the preferred one, which comes first, and addresses that failed recently are moved to the end.
+/// Addresses are shuffled within their family to spread the load.
+fn sort_addrs(sock_addrs: Vec<SocketAddr>, recently_failed: impl Fn(&SocketAddr) -> bool) -> Vec<SocketAddr> {
+    let Some(first) = sock_addrs.first() else {
+        return sock_addrs;
+    };
+    let prefer_ipv6 = first.is_ipv6();
+    let (mut preferred, mut other): (Vec<_>, Vec<_>) = sock_addrs.into_iter().partition(|it| it.is_ipv6() == prefer_ipv6);
+    preferred.shuffle(&mut thread_rng());
+    other.shuffle(&mut thread_rng());
+
+    let mut sorted = Vec::with_capacity(preferred.len() + other.len());
+    let mut preferred = preferred.into_iter();
+    let mut other = other.into_iter();
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => break,
+            (a, b) => sorted.extend(a.into_iter().chain(b)),
+        }
+    }
+    sorted.sort_by_key(|it| recently_failed(it));
+    sorted
+}
+
+/// Splits `host:port` or `[ipv6]:port`, the brackets are removed from the host.
+pub fn split_host_port(addr: &str) -> anyhow::Result<(String, u16)> {
+    match addr.rsplit_once(':') {
+        Some((host, port)) => {
+            let port = port.parse::<u16>()?;
+            let host = match host.strip_prefix('[') {
+                Some(host) => host.strip_suffix(']').ok_or(anyhow::anyhow!("invalid addr: {}", addr))?,
+                None if host.contains(':') => bail!("ipv6 addr must be bracketed: {}", addr),
+                None => host,
+            };
+            Ok((host.to_owned(), port))
+        }
+        None => bail!("invalid addr: {}", addr),
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use log::{debug, error, info};
-
-    use crate::dns::DnsResolver;
+    use log::debug;
 
     use super::*;
 
     #[tokio::test]
     async fn test_to_socket_addr() -> anyhow::Result<()> {
         env_logger::init();
-        let dns_resolver = TDNSResolver::new(DnsResolver::new(None)?);
         let target_connection_config = TargetConnectionConfig::default();
         let tcp_connector = TcpConnector::new(target_connection_config)?;
         debug!("{:?}", "start");
         let addrs = tcp_connector.to_socket_addr("www.baidu.com", 80).await?;
-        // debug!("{:?}", addrs);
+        debug!("{:?}", addrs);
         let addrs = tcp_connector.to_socket_addr("apm.gz.cvte.cn", 80).await?;
         debug!("{:?}", addrs);
         let addrs = tcp_connector.to_socket_addr("192.168.31.8", 8080).await?;
@@ -91,5 +332,66 @@ mod tests {
         debug!("tcp_stream: {:?}", tcp_stream);
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_ip_literal_to_socket_addr() -> anyhow::Result<()> {
+        // literals are not looked up, so this works without dns
+        let tcp_connector = TcpConnector::new(TargetConnectionConfig::default())?;
+        assert_eq!(tcp_connector.to_socket_addr("::1", 8080).await?, vec!["[::1]:8080".parse()?]);
+        assert_eq!(tcp_connector.to_socket_addr("192.168.31.8", 8080).await?, vec!["192.168.31.8:8080".parse()?]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sort_addrs() -> anyhow::Result<()> {
+        let v4: SocketAddr = "10.0.0.1:80".parse()?;
+        let v6_a: SocketAddr = "[2001:db8::1]:80".parse()?;
+        let v6_b: SocketAddr = "[2001:db8::2]:80".parse()?;
+
+        let sorted = sort_addrs(vec![v6_a, v6_b, v4], |_| false);
+        assert!(sorted[0].is_ipv6());
+        assert_eq!(sorted[1], v4);
+        assert!(sorted[2].is_ipv6());
+
+        let sorted = sort_addrs(vec![v6_a, v6_b, v4], |it| *it == v4 || *it == v6_a);
+        assert_eq!(sorted[0], v6_b);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_race_skips_dead_addr() -> anyhow::Result<()> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let alive = listener.local_addr()?;
+        // nothing listens on the port of a dropped listener
+        let dead = tokio::net::TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+
+        let tcp_connector = TcpConnector::new(TargetConnectionConfig::default())?;
+        let tcp_stream = tcp_connector.race("localhost", vec![dead, alive]).await?;
+        assert_eq!(tcp_stream.peer_addr()?, alive);
+        assert!(tcp_connector.failed_addrs.lock().unwrap().contains_key(&dead));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connect_retry_exhausted() -> anyhow::Result<()> {
+        let dead = tokio::net::TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+        let mut target_connection_config = TargetConnectionConfig::default();
+        target_connection_config.retry.max_attempts = 3;
+        target_connection_config.retry.backoff = Duration::from_millis(1);
+        let tcp_connector = TcpConnector::new(target_connection_config)?;
+
+        let err = tcp_connector.connect("127.0.0.1", dead.port()).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<ConnectError>(), Some(ConnectError::Io(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_split_host_port() -> anyhow::Result<()> {
+        assert_eq!(split_host_port("example.com:443")?, ("example.com".to_string(), 443));
+        assert_eq!(split_host_port("[::1]:22")?, ("::1".to_string(), 22));
+        assert!(split_host_port("::1:22").is_err());
+        assert!(split_host_port("example.com").is_err());
+        Ok(())
+    }
 }
 
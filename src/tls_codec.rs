@@ -5,6 +5,8 @@ use rustls::internal::msgs::handshake::{ClientExtension, HandshakeMessagePayload
 use tokio_util::bytes::{Bytes, BytesMut};
 use tokio_util::codec::Decoder;
 
+use crate::metrics::METRICS;
+
 pub struct TlsCodec {}
 
 impl TlsCodec {
@@ -20,6 +22,16 @@ impl Decoder for TlsCodec {
     type Error = anyhow::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let result = self.decode_client_hello(src);
+        if result.is_err() {
+            METRICS.handshake_errors.with_label_values(&["tls"]).inc();
+        }
+        result
+    }
+}
+
+impl TlsCodec {
+    fn decode_client_hello(&mut self, src: &mut BytesMut) -> anyhow::Result<Option<(Sni, Bytes)>> {
         if src.is_empty() {
             return Ok(None);
         }
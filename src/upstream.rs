@@ -0,0 +1,187 @@
+use anyhow::bail;
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::conf::UpstreamProxyConfig;
+use crate::socks5;
+use crate::socks5::TargetAddr;
+use crate::tcp_connector::ConnectError;
+
+const MAX_RESPONSE_HEADER_SIZE: usize = 16 * 1024;
+
+/// Formats `host:port`, ipv6 literals are bracketed.
+pub fn format_authority(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// Asks the parent `proxy`, already connected on `stream`, to open a tunnel to `host:port`.
+pub async fn http_connect<S>(stream: &mut S, proxy: &UpstreamProxyConfig, host: &str, port: u16) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let authority = format_authority(host, port);
+    let mut req = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", authority, authority);
+    if let Some(ref username) = proxy.username {
+        let credentials = format!("{}:{}", username, proxy.password.as_deref().unwrap_or_default());
+        req.push_str(&format!("Proxy-Authorization: Basic {}\r\n", BASE64_STANDARD.encode(credentials)));
+    }
+    req.push_str("\r\n");
+    stream.write_all(req.as_bytes()).await?;
+    stream.flush().await?;
+
+    let head = read_response_head(stream).await?;
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut response = httparse::Response::new(&mut headers);
+    if response.parse(&head)?.is_partial() {
+        bail!("incomplete response from upstream proxy {}", proxy.addr);
+    }
+    let code = response.code.unwrap_or_default();
+    if !(200..300).contains(&code) {
+        return Err(ConnectError::Upstream(format!("upstream proxy {} answered CONNECT {} with {} {}", proxy.addr, authority, code, response.reason.unwrap_or_default())).into());
+    }
+    Ok(())
+}
+
+/// SOCKS5 client handshake with the parent `proxy`, already connected on `stream`, to open a tunnel to `target`.
+pub async fn socks5_connect<S>(stream: &mut S, proxy: &UpstreamProxyConfig, target: &TargetAddr) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let methods: &[u8] = match proxy.username {
+        Some(_) => &[socks5::METHOD_NO_AUTH, socks5::METHOD_USER_PASS],
+        None => &[socks5::METHOD_NO_AUTH],
+    };
+    let mut buf = vec![socks5::SOCKS5_VERSION, methods.len() as u8];
+    buf.extend_from_slice(methods);
+    stream.write_all(&buf).await?;
+    stream.flush().await?;
+
+    let mut selected = [0u8; 2];
+    stream.read_exact(&mut selected).await?;
+    if selected[0] != socks5::SOCKS5_VERSION {
+        bail!("upstream proxy {} is not a socks5 server", proxy.addr);
+    }
+    match (selected[1], &proxy.username) {
+        (socks5::METHOD_NO_AUTH, _) => {}
+        (socks5::METHOD_USER_PASS, Some(username)) => {
+            let password = proxy.password.as_deref().unwrap_or_default();
+            if username.len() > u8::MAX as usize || password.len() > u8::MAX as usize {
+                bail!("username or password too long for upstream proxy {}", proxy.addr);
+            }
+            let mut buf = vec![socks5::USER_PASS_VERSION, username.len() as u8];
+            buf.extend_from_slice(username.as_bytes());
+            buf.push(password.len() as u8);
+            buf.extend_from_slice(password.as_bytes());
+            stream.write_all(&buf).await?;
+            stream.flush().await?;
+
+            let mut status = [0u8; 2];
+            stream.read_exact(&mut status).await?;
+            if status[1] != 0x00 {
+                return Err(ConnectError::Upstream(format!("upstream proxy {} rejected credentials of {}", proxy.addr, username)).into());
+            }
+        }
+        (method, _) => {
+            return Err(ConnectError::Upstream(format!("upstream proxy {} selected unsupported auth method: {}", proxy.addr, method)).into());
+        }
+    }
+
+    let mut buf = vec![socks5::SOCKS5_VERSION, socks5::CMD_CONNECT, 0x00];
+    target.write_to(&mut buf)?;
+    stream.write_all(&buf).await?;
+    stream.flush().await?;
+
+    let mut header = [0u8; 3];
+    stream.read_exact(&mut header).await?;
+    // BND.ADDR and BND.PORT are not used, but must be consumed
+    TargetAddr::read_from(stream).await?.ok_or(anyhow::anyhow!("invalid bind address from upstream proxy {}", proxy.addr))?;
+    if header[1] != socks5::Reply::Succeeded as u8 {
+        return Err(ConnectError::Upstream(format!("upstream proxy {} answered CONNECT {:?} with reply {}", proxy.addr, target, header[1])).into());
+    }
+    Ok(())
+}
+
+/// Reads up to the end of the response head one byte at a time, the tunneled
+/// server may talk first and its bytes must stay in the stream.
+async fn read_response_head<R: AsyncRead + Unpin>(r: &mut R) -> anyhow::Result<Vec<u8>> {
+    let mut head = Vec::with_capacity(128);
+    loop {
+        head.push(r.read_u8().await?);
+        if head.ends_with(b"\r\n\r\n") {
+            return Ok(head);
+        }
+        if head.len() >= MAX_RESPONSE_HEADER_SIZE {
+            bail!("upstream response header too large");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::conf::UpstreamProtocol;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_http_connect() -> anyhow::Result<()> {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let proxy = UpstreamProxyConfig { addr: "proxy:3128".to_string(), protocol: UpstreamProtocol::Http, username: Some("a".to_string()), password: Some("b".to_string()), remote_dns: true };
+        let fake_proxy = tokio::spawn(async move {
+            let head = read_response_head(&mut server).await.unwrap();
+            server.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\nSSH-2.0-banner").await.unwrap();
+            String::from_utf8(head).unwrap()
+        });
+
+        http_connect(&mut client, &proxy, "::1", 22).await?;
+        let request = fake_proxy.await?;
+        assert!(request.starts_with("CONNECT [::1]:22 HTTP/1.1\r\n"));
+        assert!(request.contains("Proxy-Authorization: Basic YTpi\r\n"));
+
+        let mut banner = [0u8; 14];
+        client.read_exact(&mut banner).await?;
+        assert_eq!(&banner, b"SSH-2.0-banner");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_socks5_connect() -> anyhow::Result<()> {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let proxy = UpstreamProxyConfig { addr: "proxy:1080".to_string(), protocol: UpstreamProtocol::Socks5, username: Some("a".to_string()), password: Some("b".to_string()), remote_dns: true };
+        let fake_proxy = tokio::spawn(async move {
+            let methods = socks5::read_methods(&mut server).await.unwrap();
+            assert_eq!(methods, vec![socks5::METHOD_NO_AUTH, socks5::METHOD_USER_PASS]);
+            server.write_all(&[socks5::SOCKS5_VERSION, socks5::METHOD_USER_PASS]).await.unwrap();
+            let credentials = socks5::read_user_pass(&mut server).await.unwrap();
+            assert_eq!(credentials, ("a".to_string(), "b".to_string()));
+            server.write_all(&[socks5::USER_PASS_VERSION, 0x00]).await.unwrap();
+            let request = socks5::read_request(&mut server).await.unwrap();
+            socks5::write_reply(&mut server, socks5::Reply::Succeeded, "127.0.0.1:1080".parse().unwrap()).await.unwrap();
+            request
+        });
+
+        let target = TargetAddr::Domain("example.com".to_string(), 443);
+        socks5_connect(&mut client, &proxy, &target).await?;
+        assert_eq!(fake_proxy.await?, (socks5::CMD_CONNECT, Some(target)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_http_connect_rejected() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let proxy = UpstreamProxyConfig { addr: "proxy:3128".to_string(), protocol: UpstreamProtocol::Http, username: None, password: None, remote_dns: true };
+        tokio::spawn(async move {
+            read_response_head(&mut server).await.unwrap();
+            server.write_all(b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n").await.unwrap();
+        });
+
+        let err = http_connect(&mut client, &proxy, "example.com", 443).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<ConnectError>(), Some(ConnectError::Upstream(_))));
+    }
+}